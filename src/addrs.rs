@@ -1,5 +1,5 @@
 // use axaddrspace::{GuestPhysAddr, GuestVirtAddr};
-use memory_addr::PAGE_SIZE_1G;
+use memory_addr::{PAGE_SIZE_1G, PAGE_SIZE_2M};
 
 use crate::structs::{
     EPTP_LIST_REGION_SIZE, INSTANCE_INNER_REGION_SIZE, INSTANCE_SHARED_REGION_SIZE,
@@ -12,6 +12,69 @@ pub enum FrameType {
     PT,
 }
 
+/// How a process's memory address space is mapped into GPA.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GuestMappingType {
+    /// GVA and GPA are mapped one to one, without segmentation.
+    One2OneMapping,
+    /// Coarse-grained segmentation at 2MB granularity.
+    CoarseGrainedSegmentation2M,
+    /// Coarse-grained segmentation at 1GB granularity.
+    CoarseGrainedSegmentation1G,
+}
+
+impl GuestMappingType {
+    /// Every supported mapping type.
+    pub const fn all() -> &'static [GuestMappingType] {
+        &[
+            GuestMappingType::One2OneMapping,
+            GuestMappingType::CoarseGrainedSegmentation2M,
+            GuestMappingType::CoarseGrainedSegmentation1G,
+        ]
+    }
+
+    /// Human-readable name, for logging.
+    pub const fn name(&self) -> &'static str {
+        match self {
+            GuestMappingType::One2OneMapping => "One2OneMapping",
+            GuestMappingType::CoarseGrainedSegmentation2M => "CoarseGrainedSegmentation2M",
+            GuestMappingType::CoarseGrainedSegmentation1G => "CoarseGrainedSegmentation1G",
+        }
+    }
+
+    /// Segmentation granularity in bytes, zero for [`GuestMappingType::One2OneMapping`].
+    pub const fn granularity_bytes(&self) -> usize {
+        match self {
+            GuestMappingType::One2OneMapping => 0,
+            GuestMappingType::CoarseGrainedSegmentation2M => PAGE_SIZE_2M,
+            GuestMappingType::CoarseGrainedSegmentation1G => PAGE_SIZE_1G,
+        }
+    }
+
+    /// Decode a raw config word, same as [`TryFrom<usize>`], returning it back if
+    /// it is not supported.
+    pub fn from_config_word(word: u64) -> Result<Self, u64> {
+        usize::try_from(word)
+            .ok()
+            .and_then(|granularity| Self::try_from(granularity).ok())
+            .ok_or(word)
+    }
+}
+
+impl TryFrom<usize> for GuestMappingType {
+    type Error = usize;
+
+    /// Convert from a granularity in bytes, returning it back if it is not supported.
+    fn try_from(granularity: usize) -> Result<Self, Self::Error> {
+        match granularity {
+            0 => Ok(GuestMappingType::One2OneMapping),
+            PAGE_SIZE_2M => Ok(GuestMappingType::CoarseGrainedSegmentation2M),
+            PAGE_SIZE_1G => Ok(GuestMappingType::CoarseGrainedSegmentation1G),
+            _ => Err(granularity),
+        }
+    }
+}
+
 /* Guest Process Virtual Address Space Layout (in GVA).*/
 pub const SHIM_PHYS_VIRT_OFFSET: usize = 0xffff_ff80_0000_0000;
 pub const GUEST_MEMORY_REGION_BASE_VA: usize = GUEST_MEM_REGION_BASE_PA + SHIM_PHYS_VIRT_OFFSET;
@@ -61,3 +124,65 @@ pub const GP_EPTP_LIST_REGION_BASE_PA: usize =
 ///
 /// Guest Process first region base address.
 pub const GUEST_MEM_REGION_BASE_PA: usize = PAGE_SIZE_1G;
+
+/// GPA of the segment at `segment_idx` of the guest memory region.
+///
+/// Returns `None` for [`GuestMappingType::One2OneMapping`], which is not segmented,
+/// and for segments that would reach into the guest page table at [`GUEST_PT_ROOT_PA`].
+pub fn segment_gpa(gty: GuestMappingType, segment_idx: usize) -> Option<usize> {
+    let granularity = gty.granularity_bytes();
+    if granularity == 0 {
+        return None;
+    }
+    let base = segment_idx
+        .checked_mul(granularity)?
+        .checked_add(GUEST_MEM_REGION_BASE_PA)?;
+    (base.checked_add(granularity)? <= GUEST_PT_ROOT_PA).then_some(base)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn guest_mapping_types() {
+        let all = GuestMappingType::all();
+        assert_eq!(all.len(), 3);
+        for (i, gty) in all.iter().enumerate() {
+            assert!(!gty.name().is_empty());
+            assert_eq!(
+                GuestMappingType::try_from(gty.granularity_bytes()),
+                Ok(*gty)
+            );
+            assert!(all[..i].iter().all(|other| other.name() != gty.name()));
+        }
+    }
+
+    #[test]
+    fn segment_gpa_layout() {
+        use GuestMappingType::*;
+
+        assert_eq!(segment_gpa(One2OneMapping, 0), None);
+        assert_eq!(
+            segment_gpa(CoarseGrainedSegmentation2M, 0),
+            Some(GUEST_MEM_REGION_BASE_PA)
+        );
+        assert_eq!(
+            segment_gpa(CoarseGrainedSegmentation2M, 3),
+            Some(GUEST_MEM_REGION_BASE_PA + 3 * PAGE_SIZE_2M)
+        );
+        assert_eq!(
+            segment_gpa(CoarseGrainedSegmentation1G, 3),
+            Some(GUEST_MEM_REGION_BASE_PA + 3 * PAGE_SIZE_1G)
+        );
+
+        // The last segment ends right at the guest page table.
+        let last = (GUEST_PT_ROOT_PA - GUEST_MEM_REGION_BASE_PA) / PAGE_SIZE_1G - 1;
+        assert_eq!(
+            segment_gpa(CoarseGrainedSegmentation1G, last),
+            Some(GUEST_PT_ROOT_PA - PAGE_SIZE_1G)
+        );
+        assert_eq!(segment_gpa(CoarseGrainedSegmentation1G, last + 1), None);
+        assert_eq!(segment_gpa(CoarseGrainedSegmentation2M, usize::MAX), None);
+    }
+}
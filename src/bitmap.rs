@@ -4,6 +4,8 @@ use core::{ops::Range, u64};
 
 use bitmap_allocator::BitAlloc;
 
+use crate::util::{align_up_log2, is_aligned_log2};
+
 /// A bitmap of 512 bits
 ///
 /// ## Example
@@ -27,13 +29,166 @@ use bitmap_allocator::BitAlloc;
 /// assert!(!ba.is_empty());
 /// ```
 pub type BitAlloc512 = BitAllocCascade8<BitAlloc64>;
+/// The original fixed fan-out of 8.
+pub type BitAllocCascade8<T> = BitAllocCascade<T, 8>;
+/// Cascade used by the page allocator, one sub-node per segment.
+pub type SegmentBitAllocCascade<T, const SIZE: usize> = BitAllocCascade<T, SIZE>;
 #[allow(unused)] // just for test.
 type BitAlloc4K = SegmentBitAllocCascade<BitAlloc512, 8>; // 512 * 8 = 4096
 // type BitAlloc32K = BitAllocCascade8<BitAlloc4K>; // 512 * 8 * 8 = 32768
 // pub type BitAlloc256K = BitAllocCascade8<BitAlloc32K>; // 512 * 8 * 8 * 8 = 512 * 512
 
+/// Extra queries and operations on top of [`BitAlloc`].
+pub trait BitAllocExt: BitAlloc {
+    /// Returns [`BitAlloc::CAP`], for callers that only hold a value.
+    fn capacity(&self) -> usize {
+        Self::CAP
+    }
+
+    /// Returns true if every bit is free.
+    fn is_all_free(&self) -> bool {
+        (0..Self::CAP).all(|i| self.test(i))
+    }
+
+    /// Returns the number of consecutive free bits starting at `key`, at most `max`.
+    fn free_run_len(&self, key: usize, max: usize) -> usize {
+        (key..Self::CAP.min(key + max))
+            .take_while(|&i| self.test(i))
+            .count()
+    }
+
+    /// Returns the number of free bits in `range`.
+    fn count_free_in(&self, range: Range<usize>) -> usize {
+        range.filter(|&i| self.test(i)).count()
+    }
+
+    /// Marks every bit as free, same as `insert(0..Self::CAP)`.
+    fn insert_all(&mut self) {
+        self.insert(0..Self::CAP);
+    }
+
+    /// Marks every bit as allocated, same as `remove(0..Self::CAP)`.
+    fn remove_all(&mut self) {
+        self.remove(0..Self::CAP);
+    }
+
+    /// Like [`BitAlloc::dealloc`], but returns `Err(key)` if the bit was already free.
+    fn dealloc_checked(&mut self, key: usize) -> Result<(), usize> {
+        if self.dealloc(key) { Ok(()) } else { Err(key) }
+    }
+
+    /// Like [`BitAlloc::dealloc_contiguous`], for a block allocated with `align_log2`.
+    ///
+    /// A `base` that is not `align_log2`-aligned can't start such a block, e.g. it
+    /// points into the middle of one, so it is rejected without freeing anything.
+    fn dealloc_contiguous_aligned(&mut self, base: usize, size: usize, align_log2: usize) -> bool {
+        is_aligned_log2(base, align_log2) && self.dealloc_contiguous(base, size)
+    }
+
+    /// Flips every bit in `range`, freeing allocated bits and allocating free ones.
+    ///
+    /// This is meant for diagnostics and tests: used on live allocations, it
+    /// breaks the owner's accounting.
+    fn toggle_range(&mut self, range: Range<usize>) {
+        for key in range {
+            if self.test(key) {
+                self.remove(key..key + 1);
+            } else {
+                self.insert(key..key + 1);
+            }
+        }
+    }
+
+    /// Allocates the lowest free bit at or after `hint`, wrapping around to 0 if
+    /// every bit above `hint` is in use.
+    fn alloc_from(&mut self, hint: usize) -> Option<usize> {
+        let key = (hint < Self::CAP)
+            .then(|| self.next(hint))
+            .flatten()
+            .or_else(|| self.next(0))?;
+        self.remove(key..key + 1);
+        Some(key)
+    }
+
+    /// Moves an allocated block of `size` bits from `src` to `dst` in one step, so
+    /// that no allocation can take the destination in between.
+    ///
+    /// Returns false, leaving the bitmap unchanged, if `src..src + size` is not
+    /// entirely allocated or `dst..dst + size` is not entirely free. Moving zero
+    /// bits always succeeds.
+    fn relocate(&mut self, src: usize, dst: usize, size: usize) -> bool {
+        if size == 0 {
+            return true;
+        }
+        let in_range = |start: usize| start.checked_add(size).is_some_and(|end| end <= Self::CAP);
+        if !in_range(src)
+            || !in_range(dst)
+            || self.count_free_in(src..src + size) != 0
+            || self.free_run_len(dst, size) != size
+        {
+            return false;
+        }
+        self.remove(dst..dst + size);
+        self.insert(src..src + size);
+        true
+    }
+
+    /// Compares against an earlier `baseline`, writing the keys that went from free
+    /// to allocated into `newly_allocated` and the ones that went from allocated to
+    /// free into `newly_freed`.
+    ///
+    /// Returns the number of keys of each kind, which may exceed the buffers' length,
+    /// in which case only the lowest keys are written.
+    fn diff(
+        &self,
+        baseline: &Self,
+        newly_allocated: &mut [usize],
+        newly_freed: &mut [usize],
+    ) -> (usize, usize) {
+        let (mut allocated, mut freed) = (0, 0);
+        for key in 0..Self::CAP {
+            match (baseline.test(key), self.test(key)) {
+                (true, false) => {
+                    if let Some(slot) = newly_allocated.get_mut(allocated) {
+                        *slot = key;
+                    }
+                    allocated += 1;
+                }
+                (false, true) => {
+                    if let Some(slot) = newly_freed.get_mut(freed) {
+                        *slot = key;
+                    }
+                    freed += 1;
+                }
+                _ => {}
+            }
+        }
+        (allocated, freed)
+    }
+
+    /// Calls `f(start, len, is_free)` once for every maximal run of identical bits
+    /// in `0..Self::CAP`, in ascending order.
+    ///
+    /// Runs are coalesced across sub-node boundaries.
+    fn for_each_run(&self, mut f: impl FnMut(usize, usize, bool)) {
+        let mut start = 0;
+        while start < Self::CAP {
+            let (end, is_free) = match self.next(start) {
+                Some(next) if next == start => {
+                    (start + self.free_run_len(start, Self::CAP - start), true)
+                }
+                Some(next) => (next, false),
+                None => (Self::CAP, false),
+            };
+            f(start, end - start, is_free);
+            start = end;
+        }
+    }
+}
+
+/// Implement the bit allocator by segment tree algorithm, with a fan-out of `SIZE`.
 #[repr(C)]
-pub struct SegmentBitAllocCascade<T: BitAlloc, const SIZE: usize>
+pub struct BitAllocCascade<T: BitAlloc, const SIZE: usize>
 where
     BitsImpl<{ SIZE }>: Bits,
 {
@@ -43,25 +198,25 @@ where
     sub_seg: [T; SIZE],
 }
 
-impl<T: BitAlloc, const SIZE: usize> Default for SegmentBitAllocCascade<T, SIZE>
+impl<T: BitAlloc, const SIZE: usize> Default for BitAllocCascade<T, SIZE>
 where
     BitsImpl<{ SIZE }>: Bits,
 {
     fn default() -> Self {
-        SegmentBitAllocCascade {
+        Self {
             bitset: Bitmap::new(),
             sub_seg: [T::DEFAULT; SIZE],
         }
     }
 }
 
-impl<T: BitAlloc, const SIZE: usize> BitAlloc for SegmentBitAllocCascade<T, SIZE>
+impl<T: BitAllocExt, const SIZE: usize> BitAlloc for BitAllocCascade<T, SIZE>
 where
     BitsImpl<{ SIZE }>: Bits,
 {
     const CAP: usize = T::CAP * SIZE;
 
-    const DEFAULT: Self = SegmentBitAllocCascade {
+    const DEFAULT: Self = Self {
         bitset: Bitmap::new(),
         sub_seg: [T::DEFAULT; SIZE],
     };
@@ -86,13 +241,20 @@ where
         align_log2: usize,
     ) -> Option<usize> {
         match base {
-            Some(base) => check_contiguous(self, base, Self::CAP, size, align_log2).then(|| {
-                self.remove(base..base + size);
-                base
-            }),
-            None => find_contiguous(self, Self::CAP, size, align_log2).inspect(|&base| {
-                self.remove(base..base + size);
-            }),
+            Some(base) if check_contiguous(self, base, size, align_log2) => {
+                take_contiguous(self, base, size)
+            }
+            Some(_) => None,
+            // A whole segment aligned to its own size (e.g. 2M of 4K pages): the only
+            // candidates are segment starts, so take the first fully free segment.
+            None if size == T::CAP && 1usize.checked_shl(align_log2 as u32) == Some(T::CAP) => {
+                let i = (0..SIZE).find(|&i| self.bitset.get(i) && self.sub_seg[i].is_all_free())?;
+                self.sub_seg[i].remove(0..T::CAP);
+                self.bitset.set(i, false);
+                Some(i * T::CAP)
+            }
+            None => find_contiguous(self, size, align_log2)
+                .and_then(|base| take_contiguous(self, base, size)),
         }
     }
 
@@ -103,6 +265,11 @@ where
     }
 
     fn dealloc_contiguous(&mut self, base: usize, size: usize) -> bool {
+        // Nothing to free, and `end - 1` below would underflow.
+        if size == 0 {
+            return true;
+        }
+
         let mut success = true;
         let Range { start, end } = base..base + size;
 
@@ -156,147 +323,81 @@ where
     }
 }
 
-impl<T: BitAlloc, const SIZE: usize> SegmentBitAllocCascade<T, SIZE>
+impl<T: BitAllocExt, const SIZE: usize> BitAllocExt for BitAllocCascade<T, SIZE>
 where
     BitsImpl<{ SIZE }>: Bits,
 {
-    fn for_range(&mut self, range: Range<usize>, f: impl Fn(&mut T, Range<usize>)) {
+    fn count_free_in(&self, range: Range<usize>) -> usize {
         let Range { start, end } = range;
-        assert!(start <= end);
         assert!(end <= Self::CAP);
-        for i in start / T::CAP..=(end - 1) / T::CAP {
-            let begin = if start / T::CAP == i {
-                start % T::CAP
-            } else {
-                0
-            };
-            let end = if end / T::CAP == i {
-                end % T::CAP
-            } else {
-                T::CAP
-            };
-            f(&mut self.sub_seg[i], begin..end);
-            self.bitset.set(i, !self.sub_seg[i].is_empty());
+        if start >= end {
+            return 0;
         }
+        // Sub-nodes with a cleared bit in `bitset` have no free bit at all.
+        (start / T::CAP..=(end - 1) / T::CAP)
+            .filter(|&i| self.bitset.get(i))
+            .map(|i| {
+                let begin = if start / T::CAP == i {
+                    start % T::CAP
+                } else {
+                    0
+                };
+                let end = if end / T::CAP == i {
+                    end % T::CAP
+                } else {
+                    T::CAP
+                };
+                self.sub_seg[i].count_free_in(begin..end)
+            })
+            .sum()
     }
-}
 
-impl<T: BitAlloc, const SIZE: usize> SegmentBitAllocCascade<T, SIZE>
-where
-    BitsImpl<{ SIZE }>: Bits,
-{
-    pub fn segment_is_free(&self, idx: usize) -> bool {
-        assert!(idx < SIZE);
-        self.sub_seg[idx].is_empty()
+    fn is_all_free(&self) -> bool {
+        self.bitset.is_full() && self.sub_seg.iter().all(|sub| sub.is_all_free())
     }
-}
-
-/// Implement the bit allocator by segment tree algorithm.
-#[derive(Default)]
-#[repr(C)]
-pub struct BitAllocCascade8<T: BitAlloc> {
-    /// for each bit, 1 indicates available, 0 indicates inavailable
-    bitset: u8,
-    sub: [T; 8],
-}
-
-impl<T: BitAlloc> BitAlloc for BitAllocCascade8<T> {
-    const CAP: usize = T::CAP * 8;
 
-    const DEFAULT: Self = BitAllocCascade8 {
-        bitset: 0,
-        sub: [T::DEFAULT; 8],
-    };
-
-    fn alloc(&mut self) -> Option<usize> {
-        if !self.is_empty() {
-            let i = self.bitset.trailing_zeros() as usize;
-            let res = self.sub[i].alloc().unwrap() + i * T::CAP;
-            self.bitset.set_bit(i, !self.sub[i].is_empty());
-            Some(res)
-        } else {
-            None
+    fn toggle_range(&mut self, range: Range<usize>) {
+        if !range.is_empty() {
+            self.for_range(range, |sub: &mut T, range| sub.toggle_range(range));
         }
     }
 
-    fn alloc_contiguous(
-        &mut self,
-        base: Option<usize>,
-        size: usize,
-        align_log2: usize,
-    ) -> Option<usize> {
-        match base {
-            Some(base) => check_contiguous(self, base, Self::CAP, size, align_log2).then(|| {
-                self.remove(base..base + size);
-                base
-            }),
-            None => find_contiguous(self, Self::CAP, size, align_log2).inspect(|&base| {
-                self.remove(base..base + size);
-            }),
-        }
+    fn insert_all(&mut self) {
+        self.sub_seg.iter_mut().for_each(|sub| sub.insert_all());
+        self.bitset = Bitmap::mask(SIZE);
     }
 
-    fn dealloc(&mut self, key: usize) -> bool {
-        let i = key / T::CAP;
-        self.bitset.set_bit(i, true);
-        self.sub[i].dealloc(key % T::CAP)
+    fn remove_all(&mut self) {
+        self.sub_seg.iter_mut().for_each(|sub| sub.remove_all());
+        self.bitset = Bitmap::new();
     }
 
-    fn dealloc_contiguous(&mut self, base: usize, size: usize) -> bool {
-        let mut success = true;
-        let Range { start, end } = base..base + size;
-
-        // Check if the range is valid.
-        if end > Self::CAP {
-            return false;
-        }
-
-        for i in start / T::CAP..=(end - 1) / T::CAP {
-            let begin = if start / T::CAP == i {
-                start % T::CAP
-            } else {
-                0
-            };
-            let end = if end / T::CAP == i {
-                end % T::CAP
-            } else {
+    fn free_run_len(&self, key: usize, max: usize) -> usize {
+        let mut len = 0;
+        let mut i = key / T::CAP;
+        let mut offset = key % T::CAP;
+        while len < max && i < SIZE {
+            // A fully free segment contributes all of its bits at once.
+            let run = if offset == 0 && self.sub_seg[i].is_all_free() {
                 T::CAP
-            };
-            success = success && self.sub[i].dealloc_contiguous(begin, end - begin);
-            self.bitset.set_bit(i, !self.sub[i].is_empty());
-        }
-        success
-    }
-
-    fn insert(&mut self, range: Range<usize>) {
-        self.for_range(range, |sub: &mut T, range| sub.insert(range));
-    }
-    fn remove(&mut self, range: Range<usize>) {
-        self.for_range(range, |sub: &mut T, range| sub.remove(range));
-    }
-    fn any(&self) -> bool {
-        !self.is_empty()
-    }
-    fn is_empty(&self) -> bool {
-        self.bitset == 0
-    }
-    fn test(&self, key: usize) -> bool {
-        self.sub[key / T::CAP].test(key % T::CAP)
-    }
-    fn next(&self, key: usize) -> Option<usize> {
-        let idx = key / T::CAP;
-        (idx..8).find_map(|i| {
-            if self.bitset.get_bit(i) {
-                let key = if i == idx { key - T::CAP * idx } else { 0 };
-                self.sub[i].next(key).map(|x| x + T::CAP * i)
             } else {
-                None
+                self.sub_seg[i].free_run_len(offset, max - len)
+            };
+            len += run;
+            if offset + run < T::CAP {
+                break;
             }
-        })
+            i += 1;
+            offset = 0;
+        }
+        len.min(max)
     }
 }
 
-impl<T: BitAlloc> BitAllocCascade8<T> {
+impl<T: BitAllocExt, const SIZE: usize> BitAllocCascade<T, SIZE>
+where
+    BitsImpl<{ SIZE }>: Bits,
+{
     fn for_range(&mut self, range: Range<usize>, f: impl Fn(&mut T, Range<usize>)) {
         let Range { start, end } = range;
         assert!(start <= end);
@@ -312,12 +413,29 @@ impl<T: BitAlloc> BitAllocCascade8<T> {
             } else {
                 T::CAP
             };
-            f(&mut self.sub[i], begin..end);
-            self.bitset.set_bit(i, !self.sub[i].is_empty());
+            f(&mut self.sub_seg[i], begin..end);
+            self.bitset.set(i, !self.sub_seg[i].is_empty());
         }
     }
 }
 
+impl<T: BitAllocExt, const SIZE: usize> BitAllocCascade<T, SIZE>
+where
+    BitsImpl<{ SIZE }>: Bits,
+{
+    /// Returns true if no bit of the segment at `idx` is free, i.e. it is fully allocated.
+    pub fn segment_is_full(&self, idx: usize) -> bool {
+        assert!(idx < SIZE);
+        self.sub_seg[idx].is_empty()
+    }
+
+    /// Returns true if every bit of the segment at `idx` is free.
+    pub fn segment_is_fully_available(&self, idx: usize) -> bool {
+        assert!(idx < SIZE);
+        self.sub_seg[idx].is_all_free()
+    }
+}
+
 /// A bitmap consisting of only 64 bits.
 /// BitAlloc64 acts as the leaf (except the leaf bits of course) nodes in the segment trees.
 ///
@@ -367,13 +485,12 @@ impl BitAlloc for BitAlloc64 {
         align_log2: usize,
     ) -> Option<usize> {
         match base {
-            Some(base) => check_contiguous(self, base, Self::CAP, size, align_log2).then(|| {
-                self.remove(base..base + size);
-                base
-            }),
-            None => find_contiguous(self, Self::CAP, size, align_log2).inspect(|&base| {
-                self.remove(base..base + size);
-            }),
+            Some(base) if check_contiguous(self, base, size, align_log2) => {
+                take_contiguous(self, base, size)
+            }
+            Some(_) => None,
+            None => find_contiguous(self, size, align_log2)
+                .and_then(|base| take_contiguous(self, base, size)),
         }
     }
 
@@ -384,6 +501,12 @@ impl BitAlloc for BitAlloc64 {
     }
 
     fn dealloc_contiguous(&mut self, base: usize, size: usize) -> bool {
+        if size == 0 {
+            return true;
+        }
+        if base + size > Self::CAP {
+            return false;
+        }
         if self.0.get_bits(base..base + size) == 0 {
             self.insert(base..base + size);
             return true;
@@ -411,91 +534,109 @@ impl BitAlloc for BitAlloc64 {
     }
 }
 
-fn find_contiguous(
-    ba: &impl BitAlloc,
-    capacity: usize,
+impl BitAllocExt for BitAlloc64 {
+    fn count_free_in(&self, range: Range<usize>) -> usize {
+        if range.is_empty() {
+            return 0;
+        }
+        self.0.get_bits(range).count_ones() as usize
+    }
+
+    fn is_all_free(&self) -> bool {
+        self.0 == u64::MAX
+    }
+
+    fn toggle_range(&mut self, range: Range<usize>) {
+        if range.is_empty() {
+            return;
+        }
+        assert!(range.end <= Self::CAP);
+        self.0 ^= (u64::MAX >> (64 - range.len())) << range.start;
+    }
+
+    fn insert_all(&mut self) {
+        self.0 = u64::MAX;
+    }
+
+    fn remove_all(&mut self) {
+        self.0 = 0;
+    }
+
+    fn free_run_len(&self, key: usize, max: usize) -> usize {
+        if key >= Self::CAP {
+            return 0;
+        }
+        ((self.0 >> key).trailing_ones() as usize).min(max)
+    }
+}
+
+fn find_contiguous(ba: &impl BitAllocExt, size: usize, align_log2: usize) -> Option<usize> {
+    find_contiguous_below(ba, ba.capacity(), size, align_log2)
+}
+
+/// Like [`find_contiguous`], but the run must end at or before `limit`.
+pub(crate) fn find_contiguous_below(
+    ba: &impl BitAllocExt,
+    limit: usize,
     size: usize,
     align_log2: usize,
 ) -> Option<usize> {
-    if capacity < (1 << align_log2) || ba.is_empty() {
+    let capacity = ba.capacity();
+    if !fits_alignment(capacity, align_log2) || size == 0 || ba.is_empty() {
         return None;
     }
+    let limit = limit.min(capacity);
 
-    let mut base = 0;
-    // First, we need to make sure that base is aligned.
-    if let Some(start) = ba.next(base) {
-        base = align_up_log2(start, align_log2);
-    } else {
-        return None;
-    }
-
-    let mut offset = base;
-
-    while offset < capacity {
-        if let Some(next) = ba.next(offset) {
-            if next != offset {
-                // it can be guarenteed that no bit in (offset..next) is free
-                // move to next aligned position after next-1
-                assert!(next > offset);
-                base = (((next - 1) >> align_log2) + 1) << align_log2;
-                assert_ne!(offset, next);
-                offset = base;
-                continue;
-            }
-        } else {
+    let mut offset = 0;
+    while offset < limit {
+        // First, we need to make sure that base is aligned.
+        let base = align_up_log2(ba.next(offset)?, align_log2);
+        if base.checked_add(size).is_none_or(|end| end > limit) {
             return None;
         }
-        offset += 1;
-        if offset - base == size {
+
+        // Fully free sub-nodes are counted in one step rather than bit by bit.
+        let run = ba.free_run_len(base, size);
+        if run == size {
             return Some(base);
         }
+        // Bit `base + run` is in use, continue searching right after it.
+        offset = base + run + 1;
     }
     None
 }
 
-fn check_contiguous(
-    ba: &impl BitAlloc,
-    base: usize,
-    capacity: usize,
-    size: usize,
-    align_log2: usize,
-) -> bool {
-    if capacity < (1 << align_log2) || ba.is_empty() {
+fn check_contiguous(ba: &impl BitAllocExt, base: usize, size: usize, align_log2: usize) -> bool {
+    let capacity = ba.capacity();
+    if !fits_alignment(capacity, align_log2) || size == 0 || ba.is_empty() {
         return false;
     }
 
     // First, we need to make sure that base is aligned.
-    if !is_aligned_log2(base, align_log2) {
+    if !is_aligned_log2(base, align_log2) || base.checked_add(size).is_none_or(|end| end > capacity)
+    {
         return false;
     }
 
-    let mut offset = base;
-    while offset < capacity {
-        if let Some(next) = ba.next(offset) {
-            if next != offset {
-                return false;
-            }
-            offset += 1;
-            if offset - base == size {
-                return true;
-            }
-        } else {
-            return false;
-        }
-    }
-    false
+    ba.free_run_len(base, size) == size
 }
 
-fn align_up_log2(base: usize, align_log2: usize) -> usize {
-    (base + ((1 << align_log2) - 1)) & !((1 << align_log2) - 1)
+/// Marks `base..base + size` as allocated, unless it doesn't fit in `ba`.
+fn take_contiguous(ba: &mut impl BitAllocExt, base: usize, size: usize) -> Option<usize> {
+    let end = base.checked_add(size).filter(|&end| end <= ba.capacity())?;
+    ba.remove(base..end);
+    Some(base)
 }
 
-fn is_aligned_log2(base: usize, align_log2: usize) -> bool {
-    (base & ((1 << align_log2) - 1)) == 0
+/// Returns true if a `capacity` bits long bitmap has room for one `1 << align_log2` block.
+fn fits_alignment(capacity: usize, align_log2: usize) -> bool {
+    align_log2 < usize::BITS as usize && capacity >= 1 << align_log2
 }
 
 #[cfg(test)]
 mod tests {
+    extern crate std;
+
     use super::*;
 
     #[test]
@@ -610,7 +751,11 @@ mod tests {
         ba0.remove(3..6);
         assert_eq!(ba0.next(0), Some(0));
         assert_eq!(ba0.alloc_contiguous(None, 1, 1), Some(0));
-        assert_eq!(find_contiguous(&ba0, BitAlloc4K::CAP, 2, 0), Some(1));
+        assert_eq!(find_contiguous(&ba0, 2, 0), Some(1));
+        // The capacity comes from the allocator itself, so an alignment beyond the
+        // 64 bits of `ba0` can't send the search past its end.
+        assert_eq!(ba0.capacity(), 64);
+        assert_eq!(find_contiguous(&ba0, 1, 7), None);
 
         let mut ba = BitAlloc4K::default();
         assert_eq!(BitAlloc4K::CAP, 4096);
@@ -621,7 +766,7 @@ mod tests {
         assert_eq!(ba.next(0), Some(1));
         assert_eq!(ba.next(1), Some(1));
         assert_eq!(ba.next(2), Some(2));
-        assert_eq!(find_contiguous(&ba, BitAlloc4K::CAP, 2, 0), Some(1));
+        assert_eq!(find_contiguous(&ba, 2, 0), Some(1));
         assert_eq!(ba.alloc_contiguous(None, 2, 0), Some(1));
         assert_eq!(ba.alloc_contiguous(None, 2, 3), Some(8));
         ba.remove(0..4096 - 64);
@@ -642,4 +787,573 @@ mod tests {
             assert!(ba.dealloc(i));
         }
     }
+
+    fn collect_runs(ba: &impl BitAllocExt, out: &mut [(usize, usize, bool)]) -> usize {
+        let mut n = 0;
+        ba.for_each_run(|start, len, is_free| {
+            out[n] = (start, len, is_free);
+            n += 1;
+        });
+        n
+    }
+
+    #[test]
+    fn bitalloc_for_each_run() {
+        let mut runs = [(0, 0, false); 8];
+
+        // All used.
+        let mut ba = BitAlloc64::default();
+        assert_eq!(collect_runs(&ba, &mut runs), 1);
+        assert_eq!(runs[0], (0, 64, false));
+
+        // All free.
+        ba.insert(0..64);
+        assert_eq!(collect_runs(&ba, &mut runs), 1);
+        assert_eq!(runs[0], (0, 64, true));
+
+        // Alternating patterns.
+        ba.remove(0..1);
+        ba.remove(2..4);
+        ba.remove(60..64);
+        assert_eq!(collect_runs(&ba, &mut runs), 5);
+        assert_eq!(
+            runs[..5],
+            [
+                (0, 1, false),
+                (1, 1, true),
+                (2, 2, false),
+                (4, 56, true),
+                (60, 4, false),
+            ]
+        );
+
+        // Runs are coalesced across sub-node boundaries.
+        let mut ba = BitAlloc4K::default();
+        assert_eq!(collect_runs(&ba, &mut runs), 1);
+        assert_eq!(runs[0], (0, 4096, false));
+        ba.insert(0..4096);
+        assert_eq!(collect_runs(&ba, &mut runs), 1);
+        assert_eq!(runs[0], (0, 4096, true));
+        ba.remove(500..1100);
+        assert_eq!(collect_runs(&ba, &mut runs), 3);
+        assert_eq!(
+            runs[..3],
+            [(0, 500, true), (500, 600, false), (1100, 2996, true)]
+        );
+    }
+
+    /// Reference implementation of `find_contiguous` scanning bit by bit.
+    fn find_contiguous_naive(
+        ba: &impl BitAlloc,
+        capacity: usize,
+        size: usize,
+        align_log2: usize,
+    ) -> Option<usize> {
+        (0..capacity)
+            .step_by(1 << align_log2)
+            .take_while(|base| base + size <= capacity)
+            .find(|&base| (base..base + size).all(|i| ba.test(i)))
+    }
+
+    #[test]
+    fn bitalloc_find_contiguous_matches_naive() {
+        let mut seed = 0x2545_f491_4f6c_dd1du64;
+        let mut ba = BitAlloc4K::default();
+        for round in 0..64 {
+            ba.remove(0..4096);
+            // Free a few random runs of random length.
+            for _ in 0..(round % 8 + 1) {
+                seed ^= seed << 13;
+                seed ^= seed >> 7;
+                seed ^= seed << 17;
+                let start = (seed % 4096) as usize;
+                let len = ((seed >> 32) % 1200) as usize;
+                ba.insert(start..(start + len + 1).min(4096));
+            }
+            for size in [1, 3, 64, 100, 512, 700] {
+                for align_log2 in [0, 1, 3, 6, 9] {
+                    assert_eq!(
+                        find_contiguous(&ba, size, align_log2),
+                        find_contiguous_naive(&ba, BitAlloc4K::CAP, size, align_log2),
+                        "round {round}, size {size}, align_log2 {align_log2}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn bitalloc_find_contiguous_below() {
+        let mut ba = BitAlloc4K::default();
+        ba.insert(0..4096);
+        ba.remove(0..100);
+        assert_eq!(find_contiguous_below(&ba, 4096, 10, 0), Some(100));
+        assert_eq!(find_contiguous_below(&ba, 110, 10, 0), Some(100));
+        assert_eq!(find_contiguous_below(&ba, 109, 10, 0), None);
+        assert_eq!(find_contiguous_below(&ba, 2048, 512, 9), Some(512));
+        assert_eq!(find_contiguous_below(&ba, 1023, 512, 9), None);
+        // A limit past the capacity is clamped.
+        assert_eq!(find_contiguous_below(&ba, usize::MAX, 3996, 0), Some(100));
+    }
+
+    #[test]
+    fn bitalloc_alloc_from() {
+        let mut ba = BitAlloc4K::default();
+        ba.insert(0..4096);
+        ba.remove(512..1024);
+        // The hint lands in a full segment, take the next free bit after it.
+        assert_eq!(ba.alloc_from(600), Some(1024));
+        assert_eq!(ba.alloc_from(600), Some(1025));
+        assert_eq!(ba.alloc_from(100), Some(100));
+        assert!(!ba.test(100));
+
+        // Everything above the hint is in use, wrap around to the start.
+        ba.remove(2000..4096);
+        assert_eq!(ba.alloc_from(3000), Some(0));
+        assert_eq!(ba.alloc_from(usize::MAX), Some(1));
+
+        ba.remove_all();
+        assert_eq!(ba.alloc_from(0), None);
+    }
+
+    #[test]
+    fn bitalloc_relocate() {
+        let mut ba = BitAlloc4K::default();
+        ba.insert(0..4096);
+        ba.remove(100..200);
+        ba.remove(1000..1010);
+
+        assert!(ba.relocate(100, 2000, 100));
+        assert_eq!(ba.count_free_in(100..200), 100);
+        assert_eq!(ba.count_free_in(2000..2100), 0);
+        assert_eq!(ba.count_free_in(0..4096), 4096 - 110);
+
+        // The destination overlaps an allocation, nothing changes.
+        assert!(!ba.relocate(2000, 950, 100));
+        assert!(!ba.relocate(2000, 4000, 100));
+        assert_eq!(ba.count_free_in(2000..2100), 0);
+        assert_eq!(ba.count_free_in(900..1000), 100);
+        assert_eq!(ba.count_free_in(0..4096), 4096 - 110);
+
+        // The source is not entirely allocated, or out of range.
+        assert!(!ba.relocate(1005, 3000, 10));
+        assert!(!ba.relocate(4090, 3000, 10));
+        assert!(!ba.relocate(usize::MAX, 3000, 10));
+        assert_eq!(ba.count_free_in(3000..3010), 10);
+        assert_eq!(ba.count_free_in(0..4096), 4096 - 110);
+
+        // Moving nothing always succeeds, even at the very end.
+        let mut leaf = BitAlloc64::default();
+        assert!(leaf.relocate(0, 64, 0));
+        assert!(leaf.relocate(64, 0, 0));
+        assert_eq!(leaf.free_run_len(64, 1), 0);
+        assert!(leaf.is_empty());
+    }
+
+    #[test]
+    fn bitalloc_toggle_range() {
+        let mut ba = BitAlloc4K::default();
+        ba.insert(0..4096);
+        ba.remove(500..520);
+        let expected = {
+            let mut ba = BitAlloc4K::default();
+            ba.insert(0..4096);
+            ba.remove(500..520);
+            ba
+        };
+
+        ba.toggle_range(490..530);
+        ba.toggle_range(490..530);
+        assert_same_bits(&ba, &expected);
+
+        // Across the boundary of the first two segments.
+        ba.toggle_range(505..515);
+        assert_eq!(ba.count_free_in(500..520), 10);
+        assert!((500..505).all(|i| !ba.test(i)));
+        assert!((505..515).all(|i| ba.test(i)));
+        assert!((515..520).all(|i| !ba.test(i)));
+        assert_eq!(ba.count_free_in(0..4096), 4096 - 10);
+
+        ba.toggle_range(0..4096);
+        assert_eq!(ba.count_free_in(0..4096), 10);
+        assert_eq!(ba.next(0), Some(500));
+        ba.toggle_range(0..0);
+        ba.toggle_range(3..3);
+        assert_eq!(ba.count_free_in(0..4096), 10);
+    }
+
+    #[test]
+    #[should_panic]
+    fn bitalloc64_toggle_range_out_of_bounds() {
+        BitAlloc64::default().toggle_range(0..65);
+    }
+
+    #[test]
+    fn bitalloc_segment_predicates() {
+        let mut ba = BitAlloc4K::default();
+        // Segment 0 is partially used, 1 is free, 2 is full, 3 has a single free bit.
+        ba.insert(0..1024);
+        ba.remove(3..4);
+        ba.insert(2047..2048);
+
+        let full = [false, false, true, false];
+        let available = [false, true, false, false];
+        for idx in 0..4 {
+            assert_eq!(ba.segment_is_full(idx), full[idx], "segment {idx}");
+            assert_eq!(
+                ba.segment_is_fully_available(idx),
+                available[idx],
+                "segment {idx}"
+            );
+        }
+        assert!(ba.segment_is_full(4));
+        assert!(!ba.segment_is_fully_available(4));
+    }
+
+    #[test]
+    fn bitalloc_contiguous_capacity_boundary() {
+        let mut ba = BitAlloc4K::default();
+        ba.insert(0..4096);
+        ba.remove(0..4080);
+
+        assert_eq!(ba.alloc_contiguous(Some(4090), 7, 0), None);
+        assert_eq!(ba.alloc_contiguous(Some(4096), 1, 0), None);
+        assert_eq!(ba.alloc_contiguous(Some(usize::MAX - 2), 5, 0), None);
+        assert_eq!(ba.alloc_contiguous(None, 17, 0), None);
+        assert_eq!(ba.alloc_contiguous(None, usize::MAX, 0), None);
+        assert_eq!(ba.alloc_contiguous(None, 1, 64), None);
+        assert_eq!(ba.alloc_contiguous(None, 512, 64), None);
+        assert_eq!(ba.alloc_contiguous(None, 1, 13), None);
+        // Nothing was taken by the failed attempts.
+        assert_eq!(ba.count_free_in(0..4096), 16);
+
+        assert_eq!(ba.alloc_contiguous(Some(4090), 6, 0), Some(4090));
+        assert_eq!(ba.alloc_contiguous(None, 10, 0), Some(4080));
+        assert!(ba.is_empty());
+    }
+
+    #[test]
+    fn bitalloc_diff() {
+        let mut baseline = BitAlloc4K::default();
+        baseline.insert(0..4096);
+        baseline.remove(100..110);
+        let mut ba = BitAlloc4K::default();
+        ba.insert(0..4096);
+        ba.remove(100..110);
+
+        assert_eq!(ba.alloc_contiguous(Some(510), 4, 0), Some(510));
+        ba.insert(105..107);
+        ba.remove(4095..4096);
+
+        let mut allocated = [0; 8];
+        let mut freed = [0; 8];
+        assert_eq!(ba.diff(&baseline, &mut allocated, &mut freed), (5, 2));
+        assert_eq!(allocated[..5], [510, 511, 512, 513, 4095]);
+        assert_eq!(freed[..2], [105, 106]);
+
+        // Short buffers only get the lowest keys.
+        let mut allocated = [0; 2];
+        assert_eq!(ba.diff(&baseline, &mut allocated, &mut []), (5, 2));
+        assert_eq!(allocated, [510, 511]);
+        assert_eq!(baseline.diff(&baseline, &mut [], &mut []), (0, 0));
+    }
+
+    std::thread_local! {
+        /// Per thread, so that tests running in parallel don't see each other's calls.
+        static LEAF_CALLS: core::cell::Cell<usize> = const { core::cell::Cell::new(0) };
+    }
+
+    /// A leaf that counts how often it is queried.
+    #[derive(Default)]
+    struct CountingLeaf(BitAlloc64);
+
+    impl CountingLeaf {
+        fn count(&self) {
+            LEAF_CALLS.set(LEAF_CALLS.get() + 1);
+        }
+    }
+
+    impl BitAlloc for CountingLeaf {
+        const CAP: usize = BitAlloc64::CAP;
+        const DEFAULT: Self = Self(BitAlloc64::DEFAULT);
+
+        fn alloc(&mut self) -> Option<usize> {
+            self.0.alloc()
+        }
+        fn alloc_contiguous(
+            &mut self,
+            base: Option<usize>,
+            size: usize,
+            align_log2: usize,
+        ) -> Option<usize> {
+            self.0.alloc_contiguous(base, size, align_log2)
+        }
+        fn next(&self, key: usize) -> Option<usize> {
+            self.count();
+            self.0.next(key)
+        }
+        fn dealloc(&mut self, key: usize) -> bool {
+            self.0.dealloc(key)
+        }
+        fn dealloc_contiguous(&mut self, base: usize, size: usize) -> bool {
+            self.0.dealloc_contiguous(base, size)
+        }
+        fn insert(&mut self, range: Range<usize>) {
+            self.0.insert(range)
+        }
+        fn remove(&mut self, range: Range<usize>) {
+            self.0.remove(range)
+        }
+        fn any(&self) -> bool {
+            self.0.any()
+        }
+        fn is_empty(&self) -> bool {
+            self.0.is_empty()
+        }
+        fn test(&self, key: usize) -> bool {
+            self.count();
+            self.0.test(key)
+        }
+    }
+
+    impl BitAllocExt for CountingLeaf {
+        fn is_all_free(&self) -> bool {
+            self.count();
+            self.0.is_all_free()
+        }
+        fn free_run_len(&self, key: usize, max: usize) -> usize {
+            self.count();
+            self.0.free_run_len(key, max)
+        }
+    }
+
+    #[test]
+    fn bitalloc_contiguous_skips_free_segments() {
+        let mut ba = SegmentBitAllocCascade::<BitAllocCascade8<CountingLeaf>, 8>::default();
+        ba.insert(0..4096);
+
+        LEAF_CALLS.set(0);
+        assert_eq!(ba.alloc_contiguous(None, 3000, 0), Some(0));
+        let calls = LEAF_CALLS.get();
+        // A bit-by-bit scan queries the leaves at least once per page.
+        assert!(calls < 3000 / 10, "{calls} leaf calls");
+
+        assert!(!ba.test(2999));
+        assert!(ba.test(3000));
+        assert_eq!(ba.alloc_contiguous(None, 1000, 3), Some(3000));
+        assert_eq!(ba.alloc_contiguous(None, 97, 0), None);
+        assert_eq!(ba.alloc_contiguous(None, 96, 0), Some(4000));
+        assert!(ba.is_empty());
+    }
+
+    #[test]
+    fn bitalloc_dealloc_checked() {
+        let mut ba = BitAlloc64::default();
+        ba.insert(0..64);
+        assert_eq!(ba.alloc(), Some(0));
+        assert_eq!(ba.dealloc_checked(0), Ok(()));
+        assert_eq!(ba.dealloc_checked(0), Err(0));
+
+        let mut ba = BitAlloc512::default();
+        ba.insert(0..512);
+        ba.remove(100..101);
+        assert_eq!(ba.dealloc_checked(100), Ok(()));
+        assert_eq!(ba.dealloc_checked(100), Err(100));
+        assert_eq!(ba.dealloc_checked(511), Err(511));
+
+        let mut ba = BitAlloc4K::default();
+        ba.insert(0..4096);
+        ba.remove(3000..3001);
+        assert_eq!(ba.dealloc_checked(3000), Ok(()));
+        assert_eq!(ba.dealloc_checked(3000), Err(3000));
+        assert_eq!(ba.dealloc_checked(0), Err(0));
+        assert!(ba.is_all_free());
+    }
+
+    #[test]
+    fn bitalloc_contiguous_whole_segment() {
+        let mut ba = SegmentBitAllocCascade::<BitAllocCascade8<CountingLeaf>, 8>::default();
+        ba.insert(0..4096);
+        ba.remove(100..101);
+        ba.remove(1600..1601);
+
+        LEAF_CALLS.set(0);
+        assert_eq!(ba.alloc_contiguous(None, 512, 9), Some(512));
+        let calls = LEAF_CALLS.get();
+        assert!(calls <= 16, "{calls} leaf calls");
+        assert!(ba.test(511));
+        assert!(!ba.test(512) && !ba.test(1023));
+        assert!(ba.test(1024));
+
+        // Segments 0 and 3 are not fully free.
+        assert_eq!(ba.alloc_contiguous(None, 512, 9), Some(1024));
+        assert_eq!(ba.alloc_contiguous(None, 512, 9), Some(2048));
+        assert_eq!(ba.alloc_contiguous(None, 512, 9), Some(2560));
+        assert_eq!(ba.alloc_contiguous(None, 512, 9), Some(3072));
+        assert_eq!(ba.alloc_contiguous(None, 512, 9), Some(3584));
+        assert_eq!(ba.alloc_contiguous(None, 512, 9), None);
+
+        // Same results as the generic search.
+        assert_eq!(ba.alloc_contiguous(None, 411, 0), Some(101));
+        assert_eq!(ba.alloc_contiguous(None, 412, 0), Some(1601));
+    }
+
+    fn assert_same_bits<B: BitAllocExt>(a: &B, b: &B) {
+        assert_eq!(a.is_empty(), b.is_empty());
+        assert_eq!(a.is_all_free(), b.is_all_free());
+        for i in 0..B::CAP {
+            assert_eq!(a.test(i), b.test(i), "bit {i}");
+            assert_eq!(a.next(i), b.next(i), "next {i}");
+        }
+    }
+
+    #[test]
+    fn bitalloc_insert_remove_all() {
+        let mut bulk = BitAlloc4K::default();
+        let mut range = BitAlloc4K::default();
+        bulk.insert(7..300);
+        range.insert(7..300);
+
+        bulk.insert_all();
+        range.insert(0..BitAlloc4K::CAP);
+        assert_same_bits(&bulk, &range);
+        assert!(bulk.is_all_free());
+        assert_eq!(bulk.alloc_contiguous(None, 4096, 0), Some(0));
+        assert_eq!(range.alloc_contiguous(None, 4096, 0), Some(0));
+        assert_same_bits(&bulk, &range);
+
+        bulk.insert(1000..3000);
+        range.insert(1000..3000);
+        bulk.remove_all();
+        range.remove(0..BitAlloc4K::CAP);
+        assert_same_bits(&bulk, &range);
+        assert!(bulk.is_empty());
+        assert_eq!(bulk.alloc(), None);
+
+        let mut ba = BitAlloc64::default();
+        ba.insert_all();
+        assert_eq!(ba.0, u64::MAX);
+        ba.remove_all();
+        assert!(ba.is_empty());
+    }
+
+    #[test]
+    fn bitalloc_dealloc_contiguous_bounds() {
+        let mut ba = BitAlloc64::default();
+        assert!(ba.dealloc_contiguous(10, 0));
+        assert!(ba.dealloc_contiguous(64, 0));
+        assert!(ba.is_empty());
+        assert!(!ba.dealloc_contiguous(60, 5));
+        assert!(ba.dealloc_contiguous(60, 4));
+        assert_eq!(ba.next(0), Some(60));
+
+        let mut ba = BitAlloc512::default();
+        assert!(ba.dealloc_contiguous(0, 0));
+        assert!(ba.dealloc_contiguous(512, 0));
+        assert!(ba.is_empty());
+        assert!(!ba.dealloc_contiguous(500, 13));
+        assert!(ba.dealloc_contiguous(500, 12));
+        assert!((500..512).all(|i| ba.test(i)));
+        assert_eq!(ba.next(0), Some(500));
+
+        let mut ba = BitAlloc4K::default();
+        assert!(ba.dealloc_contiguous(0, 0));
+        assert!(ba.dealloc_contiguous(4096, 0));
+        assert!(ba.is_empty());
+        assert!(!ba.dealloc_contiguous(4000, 97));
+        assert!(ba.dealloc_contiguous(4000, 96));
+        assert!((4000..4096).all(|i| ba.test(i)));
+        assert_eq!(ba.next(0), Some(4000));
+    }
+
+    #[test]
+    fn bitalloc_count_free_in() {
+        let mut ba = BitAlloc64::default();
+        ba.insert(4..20);
+        assert_eq!(ba.count_free_in(0..64), 16);
+        assert_eq!(ba.count_free_in(10..30), 10);
+        assert_eq!(ba.count_free_in(10..10), 0);
+
+        let mut ba = BitAlloc4K::default();
+        assert_eq!(ba.count_free_in(0..4096), 0);
+        ba.insert(0..4096);
+        assert_eq!(ba.count_free_in(0..4096), 4096);
+        ba.remove(500..600);
+        ba.remove(1000..2048);
+        ba.remove(4090..4091);
+
+        let naive = |range: Range<usize>| range.filter(|&i| ba.test(i)).count();
+        for range in [
+            0..4096,
+            0..0,
+            511..513,
+            450..1100,
+            // Within a fully allocated segment.
+            1100..1500,
+            1024..2100,
+            2047..4096,
+            4000..4096,
+        ] {
+            assert_eq!(
+                ba.count_free_in(range.clone()),
+                naive(range.clone()),
+                "{range:?}"
+            );
+        }
+        assert_eq!(ba.count_free_in(450..1100), 450);
+        assert_eq!(ba.count_free_in(1024..2100), 52);
+    }
+
+    #[test]
+    fn bitalloc_cascade_fan_out() {
+        let mut ba = BitAllocCascade::<BitAlloc64, 2>::default();
+        assert_eq!(BitAllocCascade::<BitAlloc64, 2>::CAP, 128);
+        ba.insert(0..128);
+        assert_eq!(ba.alloc(), Some(0));
+        assert_eq!(ba.alloc_contiguous(None, 64, 6), Some(64));
+        assert_eq!(ba.alloc_contiguous(None, 64, 0), None);
+        assert_eq!(ba.alloc_contiguous(None, 63, 0), Some(1));
+        assert!(ba.is_empty());
+        assert!(ba.dealloc(0));
+        assert!(!ba.dealloc(0));
+        assert!(ba.dealloc_contiguous(64, 64));
+        assert_eq!(ba.next(1), Some(64));
+
+        let mut ba = BitAllocCascade::<BitAlloc64, 16>::default();
+        assert_eq!(BitAllocCascade::<BitAlloc64, 16>::CAP, 1024);
+        ba.insert(0..1024);
+        ba.remove(0..100);
+        assert_eq!(ba.alloc_contiguous(None, 200, 7), Some(128));
+        assert_eq!(ba.alloc_contiguous(Some(900), 124, 2), Some(900));
+        assert_eq!(ba.count_free_in(0..1024), 1024 - 100 - 200 - 124);
+        assert_eq!(ba.alloc(), Some(100));
+        assert!(ba.dealloc_contiguous(900, 124));
+        assert!(ba.dealloc(100));
+
+        let mut ba = BitAllocCascade::<BitAllocCascade<BitAlloc64, 4>, 3>::default();
+        assert_eq!(
+            BitAllocCascade::<BitAllocCascade::<BitAlloc64, 4>, 3>::CAP,
+            768
+        );
+        ba.insert(0..768);
+        assert_eq!(ba.alloc_contiguous(None, 700, 0), Some(0));
+        assert_eq!(ba.alloc_contiguous(None, 100, 0), None);
+        assert!(ba.dealloc_contiguous(0, 700));
+        assert!(ba.is_all_free());
+    }
+
+    #[test]
+    fn bitalloc_dealloc_contiguous_aligned() {
+        let mut ba = BitAlloc4K::default();
+        ba.insert(0..BitAlloc4K::CAP);
+        assert_eq!(ba.alloc_contiguous(None, 16, 4), Some(0));
+        assert_eq!(ba.alloc_contiguous(None, 16, 4), Some(16));
+
+        // The middle of the second block.
+        assert!(!ba.dealloc_contiguous_aligned(24, 8, 4));
+        assert!(!ba.test(24));
+        assert!(ba.dealloc_contiguous_aligned(16, 16, 4));
+        assert!((16..32).all(|i| ba.test(i)));
+        assert_eq!(ba.alloc_contiguous(None, 16, 4), Some(16));
+    }
 }
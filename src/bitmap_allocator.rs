@@ -1,9 +1,12 @@
+use core::ops::Range;
+
 use allocator::{AllocError, AllocResult, BaseAllocator};
 use bitmap_allocator::BitAlloc;
 use bitmaps::{Bitmap, Bits, BitsImpl};
 use memory_addr::{PAGE_SIZE_1G as MAX_ALIGN_1GB, align_down, align_up, is_aligned};
 
-use crate::bitmap::{BitAlloc512, SegmentBitAllocCascade};
+use crate::addrs::GuestMappingType;
+use crate::bitmap::{BitAlloc512, BitAllocExt, SegmentBitAllocCascade, find_contiguous_below};
 
 /// Page-granularity allocator.
 /// refer to [`PageAllocator`] in https://github.com/arceos-org/allocator.git for more details.
@@ -23,14 +26,104 @@ pub trait PageAllocator: BaseAllocator {
         align_pow2: usize,
     ) -> AllocResult<usize>;
 
-    /// Returns the total number of memory pages.
+    /// Returns the total number of memory pages the allocator was initialized with.
     fn total_pages(&self) -> usize;
 
     /// Returns the number of allocated memory pages.
     fn used_pages(&self) -> usize;
 
-    /// Returns the number of available memory pages.
+    /// Returns the number of memory pages that can currently be allocated.
     fn available_pages(&self) -> usize;
+
+    /// Returns the size of a page in bytes.
+    fn page_size(&self) -> usize;
+
+    /// Returns the range of addresses the allocator may hand out.
+    fn managed_range(&self) -> Range<usize>;
+
+    /// Returns the total size of memory in bytes.
+    fn total_bytes(&self) -> usize {
+        self.total_pages() * self.page_size()
+    }
+
+    /// Returns the size of allocated memory in bytes.
+    fn used_bytes(&self) -> usize {
+        self.used_pages() * self.page_size()
+    }
+
+    /// Returns the size of available memory in bytes.
+    fn available_bytes(&self) -> usize {
+        self.available_pages() * self.page_size()
+    }
+}
+
+/// Outcome of [`SegmentBitmapPageAllocator::increase_segment_at`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SegmentGrowResult {
+    /// The segment was added to the allocator.
+    Grown,
+    /// The segment was already backed, nothing changed.
+    AlreadyBacked,
+    /// The segment lies outside the range the allocator can manage.
+    OutOfRange,
+}
+
+/// A consistent snapshot of a [`SegmentBitmapPageAllocator`]'s state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AllocatorStats {
+    /// Base address of the allocator.
+    pub base: usize,
+    /// Size of a page in bytes.
+    pub page_size: usize,
+    /// Total number of pages.
+    pub total_pages: usize,
+    /// Number of allocated pages.
+    pub used_pages: usize,
+    /// Number of segments with physical memory backing.
+    pub backed_segments: usize,
+    /// Length in pages of the largest run of free pages.
+    pub largest_free: usize,
+}
+
+impl core::fmt::Display for AllocatorStats {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "base {:#x}, page size {:#x}, {}/{} pages used, {} segments backed, largest free run {} pages",
+            self.base,
+            self.page_size,
+            self.used_pages,
+            self.total_pages,
+            self.backed_segments,
+            self.largest_free
+        )
+    }
+}
+
+/// A range of contiguous frames handed out by [`SegmentBitmapPageAllocator::alloc_frame_range`].
+///
+/// It remembers its own size, so that it can't be freed with the wrong count.
+#[must_use]
+#[derive(Debug, PartialEq, Eq)]
+pub struct FrameRange {
+    /// Address of the first frame.
+    pub base: usize,
+    /// Number of frames.
+    pub num_pages: usize,
+    /// Size of a frame in bytes.
+    pub page_size: usize,
+}
+
+impl FrameRange {
+    /// Size of the range in bytes.
+    pub fn bytes(&self) -> usize {
+        self.num_pages * self.page_size
+    }
+
+    /// Whether `addr` lies within the range.
+    pub fn contains(&self, addr: usize) -> bool {
+        (self.base..self.base + self.bytes()).contains(&addr)
+    }
 }
 
 /// A Segment-aware page-granularity memory allocator based on the [bitmap_allocator].
@@ -39,6 +132,14 @@ pub trait PageAllocator: BaseAllocator {
 /// allocated.
 ///
 /// The `self.page_size` must be a power of two.
+///
+/// The bitmap holds at most `SIZE * 512` pages, counted from [`Self::base`], and
+/// initializing it with a larger region panics.
+///
+/// Allocation is first-fit: every request returns the lowest suitable address,
+/// and there is no cursor or other history besides the bitmap itself. A given
+/// sequence of allocations and deallocations therefore always yields the same
+/// addresses.
 #[repr(C)]
 pub struct SegmentBitmapPageAllocator<const SIZE: usize>
 where
@@ -46,21 +147,35 @@ where
 {
     base: usize,
     segment_granularity: usize,
+    /// Index of the segment the allocator was initialized with.
+    initial_segment: usize,
 
     page_size: usize,
     used_pages: usize,
     total_pages: usize,
 
+    /// Whether allocation boundaries are recorded in `alloc_heads`.
+    track_allocations: bool,
+
     /// Mark if the physical memory backend is allocated for this sub segments.
     /// 1 indicates allocated, 0 indicates not allocated.
     allocated_bitset: Bitmap<SIZE>,
     inner: SegmentBitAllocCascade<BitAlloc512, SIZE>,
+    /// Set at the first page of every live allocation, only maintained while
+    /// `track_allocations` is enabled.
+    alloc_heads: SegmentBitAllocCascade<BitAlloc512, SIZE>,
+    /// Set at every page that must never be handed out, see
+    /// [`Self::reserve_permanent`].
+    reserved: SegmentBitAllocCascade<BitAlloc512, SIZE>,
 }
 
 impl<const SIZE: usize> SegmentBitmapPageAllocator<{ SIZE }>
 where
     BitsImpl<{ SIZE }>: Bits,
 {
+    /// Number of pages in a segment, fixed by the inner bitmap of each segment.
+    pub const PAGES_PER_SEGMENT: usize = BitAlloc512::CAP;
+
     pub fn base(&self) -> usize {
         self.base
     }
@@ -79,7 +194,81 @@ where
         self.total_pages
     }
 
+    /// Address of the page at `bit_idx` in the inner bitmap.
+    pub fn page_addr(&self, bit_idx: usize) -> usize {
+        debug_assert!(bit_idx < SegmentBitAllocCascade::<BitAlloc512, SIZE>::CAP);
+        self.base + bit_idx * self.page_size
+    }
+
+    /// Index in the inner bitmap of the page at `addr`, `None` if `addr` is not
+    /// page aligned or out of the allocator's range.
+    pub fn bit_idx(&self, addr: usize) -> Option<usize> {
+        if addr < self.base || !is_aligned(addr, self.page_size) {
+            return None;
+        }
+        let bit_idx = (addr - self.base) / self.page_size;
+        (bit_idx < SegmentBitAllocCascade::<BitAlloc512, SIZE>::CAP).then_some(bit_idx)
+    }
+
+    /// Range of the pages of the segment at `segment_idx` in the inner bitmap.
+    fn segment_bits(&self, segment_idx: usize) -> Range<usize> {
+        let pages_per_segment = self.segment_granularity / self.page_size;
+        let start = segment_idx * pages_per_segment;
+        start..start + pages_per_segment
+    }
+
+    /// Whether every segment `range` of the inner bitmap touches is backed.
+    fn is_backed_range(&self, range: Range<usize>) -> bool {
+        if range.is_empty() {
+            return true;
+        }
+        let pages_per_segment = self.segment_granularity / self.page_size;
+        (range.start / pages_per_segment..=(range.end - 1) / pages_per_segment)
+            .all(|segment_idx| self.is_segment_backed(segment_idx))
+    }
+
+    /// Validate `num_pages` pages at `pos` for being freed, returning their range in
+    /// the inner bitmap.
+    ///
+    /// The range must be non-empty and within the allocator, or it is rejected with
+    /// [`AllocError::InvalidParam`]. Pages of unbacked segments, whose bits read as
+    /// allocated, and permanently reserved pages can never be freed, they are
+    /// rejected with [`AllocError::NotAllocated`].
+    fn dealloc_range(&self, pos: usize, num_pages: usize) -> AllocResult<Range<usize>> {
+        let start = self.bit_idx(pos).ok_or(AllocError::InvalidParam)?;
+        let end = start
+            .checked_add(num_pages)
+            .filter(|&end| num_pages > 0 && end <= SegmentBitAllocCascade::<BitAlloc512, SIZE>::CAP)
+            .ok_or(AllocError::InvalidParam)?;
+        if !self.is_backed_range(start..end) || self.is_reserved_in(start..end) {
+            return Err(AllocError::NotAllocated);
+        }
+        Ok(start..end)
+    }
+
+    /// Whether any page in `range` of the inner bitmap is permanently reserved.
+    fn is_reserved_in(&self, range: Range<usize>) -> bool {
+        self.reserved
+            .next(range.start)
+            .is_some_and(|idx| idx < range.end)
+    }
+
+    /// Take the permanently reserved pages in `range` back out of the inner bitmap.
+    fn exclude_reserved(&mut self, range: Range<usize>) {
+        let mut idx = range.start;
+        while let Some(reserved) = self.reserved.next(idx).filter(|&i| i < range.end) {
+            self.inner.remove(reserved..reserved + 1);
+            idx = reserved + 1;
+        }
+    }
+
     /// Constructs a new `BitmapPageAllocator` with the given page size from raw memory.
+    ///
+    /// Every segment the region touches is marked backed. `start` only needs to be
+    /// page aligned: the parts of the first and last segments outside the region
+    /// are permanently reserved instead of populated.
+    ///
+    /// A segment must span exactly [`Self::PAGES_PER_SEGMENT`] pages.
     pub fn init_with_page_size(
         &mut self,
         page_size: usize,
@@ -89,52 +278,531 @@ where
     ) {
         assert!(page_size.is_power_of_two());
         assert!(segment_granularity.is_power_of_two());
-        assert!(is_aligned(start, segment_granularity));
+        assert!(
+            segment_granularity / page_size == Self::PAGES_PER_SEGMENT,
+            "a segment must span exactly {} pages",
+            Self::PAGES_PER_SEGMENT
+        );
+        assert!(is_aligned(start, page_size));
 
         self.page_size = page_size;
         self.segment_granularity = segment_granularity;
 
-        self.allocated_bitset.set(
-            align_down(start, segment_granularity) / segment_granularity,
-            true,
-        );
-
         self.init(start, size);
+
+        // Segments are indexed relative to `self.base`, which is only known after `init`.
+        let start_idx = (start - self.base) / page_size;
+        let end_idx = start_idx + self.total_pages;
+        let first_segment = start_idx / Self::PAGES_PER_SEGMENT;
+        let last_segment = end_idx.saturating_sub(1).max(start_idx) / Self::PAGES_PER_SEGMENT;
+        for segment_idx in first_segment..=last_segment {
+            self.allocated_bitset.set(segment_idx, true);
+        }
+        self.initial_segment = first_segment;
+
+        // The parts of the first and last segments outside the region are not
+        // memory, keep them out for good.
+        let head = self.segment_bits(first_segment).start..start_idx;
+        let tail = end_idx..self.segment_bits(last_segment).end;
+        for range in [head, tail] {
+            if !range.is_empty() {
+                self.reserved.insert(range);
+            }
+        }
+    }
+
+    /// Place a fresh allocator at `ptr` and initialize it, see [`Self::init_with_page_size`].
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be valid for writes, properly aligned, and not aliased for the
+    /// lifetime `'a`. Whatever it pointed to is overwritten without being dropped.
+    pub unsafe fn new_in_place<'a>(
+        ptr: *mut Self,
+        page_size: usize,
+        segment_granularity: usize,
+        start: usize,
+        size: usize,
+    ) -> &'a mut Self {
+        // SAFETY: the caller guarantees `ptr` is valid for writes and aligned, and
+        // all-zero is a valid, empty allocator state.
+        let allocator = unsafe {
+            ptr.write_bytes(0, 1);
+            &mut *ptr
+        };
+        allocator.init_with_page_size(page_size, segment_granularity, start, size);
+        allocator
+    }
+
+    /// Reinterpret an already populated allocator state at `ptr`, e.g. one restored
+    /// from a snapshot, without running `init` over it.
+    ///
+    /// The contents are trusted as is, check them with [`Self::verify`] before use.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be valid for reads and writes, properly aligned, hold a valid
+    /// `Self`, and not be accessed through any other reference while the returned
+    /// one is alive.
+    pub unsafe fn from_raw_state<'a>(ptr: *mut Self) -> &'a mut Self {
+        // SAFETY: guaranteed by the caller.
+        unsafe { &mut *ptr }
+    }
+
+    /// Sanity-check the allocator state, for states not built by `init`.
+    ///
+    /// Only the geometry and the page counters are checked, not the bitmaps.
+    pub fn verify(&self) -> bool {
+        let cap = SegmentBitAllocCascade::<BitAlloc512, SIZE>::CAP;
+        self.page_size.is_power_of_two()
+            && self.segment_granularity.is_power_of_two()
+            && self.segment_granularity / self.page_size == Self::PAGES_PER_SEGMENT
+            && is_aligned(self.base, MAX_ALIGN_1GB)
+            && self.total_pages <= cap
+            && self.used_pages <= self.backed_segment_count() * Self::PAGES_PER_SEGMENT
     }
 
-    pub fn increase_segment_at(&mut self, segment_base: usize) -> bool {
+    /// Like [`Self::init_with_page_size`], with the segment granularity derived from
+    /// the guest mapping type it backs, so that the two can't diverge. The page size
+    /// follows from it, e.g. 4K pages for 2M segments and 2M pages for 1G segments.
+    ///
+    /// Panics on [`GuestMappingType::One2OneMapping`], which is not segmented.
+    pub fn init_with_mapping_type(
+        &mut self,
+        mapping_type: GuestMappingType,
+        start: usize,
+        size: usize,
+    ) {
+        assert!(
+            mapping_type != GuestMappingType::One2OneMapping,
+            "One2OneMapping can't back a segmented allocator"
+        );
+        let segment_granularity = mapping_type.granularity_bytes();
+        self.init_with_page_size(
+            segment_granularity / Self::PAGES_PER_SEGMENT,
+            segment_granularity,
+            start,
+            size,
+        );
+    }
+
+    #[must_use]
+    pub fn increase_segment_at(&mut self, segment_base: usize) -> SegmentGrowResult {
         assert!(is_aligned(segment_base, self.segment_granularity));
 
-        let segment_idx = segment_base / self.segment_granularity;
+        if segment_base < self.base {
+            return SegmentGrowResult::OutOfRange;
+        }
+        let segment_idx = (segment_base - self.base) / self.segment_granularity;
+        if segment_idx >= SIZE {
+            return SegmentGrowResult::OutOfRange;
+        }
         // Check if the segment is already allocated.
         if self.allocated_bitset.get(segment_idx) {
-            return false;
+            return SegmentGrowResult::AlreadyBacked;
         }
 
         // Mark the segment as allocated.
         self.allocated_bitset.set(segment_idx, true);
 
-        // Allocate a new segment.
-        let start = segment_idx * self.segment_granularity;
-        let end = start + self.segment_granularity;
-
         // Initialize the inner allocator for the new segment.
+        self.inner.insert(self.segment_bits(segment_idx));
+        self.exclude_reserved(self.segment_bits(segment_idx));
+
+        SegmentGrowResult::Grown
+    }
+
+    /// Allocate contiguous pages, backing new segments on demand.
+    ///
+    /// When the allocation fails with [`AllocError::NoMemory`], the lowest unbacked
+    /// segment is passed to `grow_fn`, which provisions its physical backing, then
+    /// it is added through [`Self::increase_segment_at`] and the allocation is retried.
+    /// This repeats until it succeeds or no unbacked segment is left.
+    /// If `grow_fn` returns false, the allocation fails with [`AllocError::NoMemory`].
+    pub fn alloc_pages_growing(
+        &mut self,
+        num_pages: usize,
+        align_pow2: usize,
+        mut grow_fn: impl FnMut(usize) -> bool,
+    ) -> AllocResult<usize> {
+        loop {
+            match self.alloc_pages(num_pages, align_pow2) {
+                Err(AllocError::NoMemory) => {}
+                res => return res,
+            }
+
+            let segment_idx = self
+                .allocated_bitset
+                .first_false_index()
+                .filter(|&idx| idx < SIZE)
+                .ok_or(AllocError::NoMemory)?;
+            let segment_base = self.base + segment_idx * self.segment_granularity;
+            if !grow_fn(segment_base) {
+                return Err(AllocError::NoMemory);
+            }
+            let res = self.increase_segment_at(segment_base);
+            debug_assert_eq!(res, SegmentGrowResult::Grown);
+        }
+    }
+
+    /// Allocate pages at a specific address, backing the segments it touches first.
+    ///
+    /// Every unbacked segment in the range is passed to `grow_fn`, which provisions
+    /// its physical backing, then added through [`Self::increase_segment_at`]. If
+    /// `grow_fn` returns false or the placement fails, the segments grown by this
+    /// call are freed again, and the caller is responsible for releasing their
+    /// backing.
+    pub fn alloc_pages_at_growing(
+        &mut self,
+        base: usize,
+        num_pages: usize,
+        align_pow2: usize,
+        mut grow_fn: impl FnMut(usize) -> bool,
+    ) -> AllocResult<usize> {
+        let start = self.bit_idx(base).ok_or(AllocError::InvalidParam)?;
+        let last = num_pages
+            .checked_sub(1)
+            .and_then(|n| start.checked_add(n))
+            .ok_or(AllocError::InvalidParam)?;
+        let pages_per_segment = self.segment_granularity / self.page_size;
+        let segments = start / pages_per_segment..last / pages_per_segment + 1;
+
+        let mut grown = Bitmap::<SIZE>::new();
+        let mut all_backed = true;
+        for segment_idx in segments {
+            if self.is_segment_backed(segment_idx) {
+                continue;
+            }
+            let segment_base = self.base + segment_idx * self.segment_granularity;
+            if segment_idx >= SIZE
+                || !grow_fn(segment_base)
+                || self.increase_segment_at(segment_base) != SegmentGrowResult::Grown
+            {
+                all_backed = false;
+                break;
+            }
+            grown.set(segment_idx, true);
+        }
+
+        let res = if all_backed {
+            self.alloc_pages_at(base, num_pages, align_pow2)
+        } else {
+            Err(AllocError::NoMemory)
+        };
+        if res.is_err() {
+            for segment_idx in &grown {
+                self.free_segment(segment_idx);
+            }
+        }
+        res
+    }
+
+    /// Enable or disable recording of allocation boundaries, which
+    /// [`Self::dealloc_pages_exact`] relies on.
+    ///
+    /// Any previously recorded boundary is dropped, so only allocations made
+    /// while tracking is enabled can be freed exactly.
+    pub fn set_track_allocations(&mut self, enabled: bool) {
+        self.track_allocations = enabled;
+        self.alloc_heads.remove_all();
+    }
+
+    /// Like [`PageAllocator::dealloc_pages`], for pages allocated with `align_pow2`.
+    ///
+    /// Freeing from the middle of an aligned allocation leaves a hole that breaks
+    /// later aligned allocations, so a `pos` that is not `align_pow2`-aligned is
+    /// rejected with [`AllocError::InvalidParam`] and nothing is freed.
+    pub fn dealloc_pages_aligned(
+        &mut self,
+        pos: usize,
+        num_pages: usize,
+        align_pow2: usize,
+    ) -> AllocResult {
+        if num_pages == 0
+            || !align_pow2.is_power_of_two()
+            || !is_aligned(align_pow2, self.page_size)
+            || !is_aligned(pos, self.page_size)
+        {
+            return Err(AllocError::InvalidParam);
+        }
+        let align_log2 = (align_pow2 / self.page_size).trailing_zeros() as usize;
+        if !is_aligned(pos, align_pow2) {
+            warn!("Try to free pages at {pos:#x}, which is not aligned to {align_pow2:#x}");
+            return Err(AllocError::InvalidParam);
+        }
+
+        let range = self.dealloc_range(pos, num_pages).and_then(|range| {
+            self.inner
+                .dealloc_contiguous_aligned(range.start, num_pages, align_log2)
+                .then_some(range)
+                .ok_or(AllocError::NotAllocated)
+        });
+        let range = range.inspect_err(|_| {
+            warn!("Try to free pages that are not allocated: {pos:#x}, {num_pages}");
+        })?;
+        self.used_pages -= num_pages;
+        if self.track_allocations {
+            self.alloc_heads.remove(range);
+        }
+        Ok(())
+    }
+
+    /// Deallocate pages only if they form exactly one recorded allocation.
+    ///
+    /// Freeing a part of an allocation, or a range spanning several of them, is
+    /// rejected with [`AllocError::NotAllocated`] and leaves the allocator unchanged.
+    /// Requires allocation tracking, see [`Self::set_track_allocations`].
+    pub fn dealloc_pages_exact(&mut self, pos: usize, num_pages: usize) -> AllocResult {
+        if !self.track_allocations {
+            return Err(AllocError::InvalidParam);
+        }
+        let Range { start, end } = self.dealloc_range(pos, num_pages)?;
+
+        // The range must start an allocation, contain no other allocation start,
+        // be fully allocated, and not be followed by the rest of the allocation.
+        // Past a backed range, an unbacked segment can only start at `end`.
+        let pages_per_segment = self.segment_granularity / self.page_size;
+        let is_exact = self.alloc_heads.test(start)
+            && self.alloc_heads.next(start + 1).is_none_or(|i| i >= end)
+            && self.inner.next(start).is_none_or(|i| i >= end)
+            && (end == SegmentBitAllocCascade::<BitAlloc512, SIZE>::CAP
+                || self.inner.test(end)
+                || self.alloc_heads.test(end)
+                || self.reserved.test(end)
+                || !self.is_segment_backed(end / pages_per_segment));
+        if !is_exact {
+            return Err(AllocError::NotAllocated);
+        }
+
+        self.alloc_heads.remove(start..start + 1);
         self.inner.insert(start..end);
+        self.used_pages -= num_pages;
+        Ok(())
+    }
+
+    /// Compute the moves that would pack the recorded allocations toward the base.
+    ///
+    /// Each `(from_base, to_base)` move slides one allocation down. Allocations keep
+    /// their relative order and never slide past a page they can't account for,
+    /// i.e. an untracked allocation, a reserved page or an unbacked segment. Moves are listed in
+    /// ascending address order, so performing them in order never overwrites data
+    /// that is yet to be moved. Their original alignment is not preserved.
+    ///
+    /// Up to `out.len()` moves are written to `out`, the returned count is the total
+    /// number of moves, which may be larger.
+    /// Requires allocation tracking, see [`Self::set_track_allocations`].
+    pub fn compaction_plan(&self, out: &mut [(usize, usize)]) -> usize {
+        let cap = SegmentBitAllocCascade::<BitAlloc512, SIZE>::CAP;
+        let pages_per_segment = self.segment_granularity / self.page_size;
+        // Whether the allocated page at `idx` continues the allocation before it.
+        let continues = |idx: usize| {
+            !self.inner.test(idx)
+                && !self.alloc_heads.test(idx)
+                && !self.reserved.test(idx)
+                && self.is_segment_backed(idx / pages_per_segment)
+        };
+
+        let mut count = 0;
+        // Lowest page the next allocation may slide down to.
+        let mut cursor = 0;
+        let mut idx = 0;
+        while idx < cap {
+            if self.inner.test(idx) {
+                idx += 1;
+                continue;
+            }
+            if !self.alloc_heads.test(idx) {
+                // Nothing may slide past a page no allocation accounts for.
+                idx += 1;
+                cursor = idx;
+                continue;
+            }
 
-        true
+            let mut end = idx + 1;
+            while end < cap && continues(end) {
+                end += 1;
+            }
+            if cursor < idx {
+                if let Some(slot) = out.get_mut(count) {
+                    *slot = (self.page_addr(idx), self.page_addr(cursor));
+                }
+                count += 1;
+            }
+            cursor += end - idx;
+            idx = end;
+        }
+        count
+    }
+
+    /// Account for `num_pages` allocated at `start`, returning their address.
+    fn on_alloc(&mut self, start: usize, num_pages: usize) -> usize {
+        self.used_pages += num_pages;
+        if self.track_allocations {
+            self.alloc_heads.insert(start..start + 1);
+        }
+        self.page_addr(start)
+    }
+
+    /// Like [`PageAllocator::alloc_pages`], with the alignment given as log2 of a
+    /// number of pages rather than in bytes.
+    pub fn alloc_pages_log2(
+        &mut self,
+        num_pages: usize,
+        align_log2_pages: usize,
+    ) -> AllocResult<usize> {
+        // Check if the alignment is valid.
+        if align_log2_pages > (MAX_ALIGN_1GB / self.page_size).trailing_zeros() as usize {
+            return Err(AllocError::InvalidParam);
+        }
+        match (num_pages, align_log2_pages) {
+            (0, _) => return Err(AllocError::InvalidParam),
+            (1, 0) => self.inner.alloc(),
+            _ => self
+                .inner
+                .alloc_contiguous(None, num_pages, align_log2_pages),
+        }
+        .ok_or(AllocError::NoMemory)
+        .map(|idx| self.on_alloc(idx, num_pages))
+    }
+
+    /// Like [`PageAllocator::alloc_pages`], returning the allocation as a [`FrameRange`].
+    pub fn alloc_frame_range(
+        &mut self,
+        num_pages: usize,
+        align_pow2: usize,
+    ) -> AllocResult<FrameRange> {
+        self.alloc_pages(num_pages, align_pow2)
+            .map(|base| FrameRange {
+                base,
+                num_pages,
+                page_size: self.page_size,
+            })
+    }
+
+    /// Deallocate a range returned by [`Self::alloc_frame_range`].
+    pub fn dealloc_frame_range(&mut self, range: FrameRange) {
+        debug_assert_eq!(range.page_size, self.page_size);
+        self.dealloc_pages(range.base, range.num_pages);
+    }
+
+    /// Deallocate individual pages, e.g. the frames of a scatter-gather mapping.
+    ///
+    /// Addresses that are not allocated pages, including pages of unbacked
+    /// segments, are skipped. Returns the number of pages actually freed.
+    pub fn dealloc_pages_scattered(&mut self, addrs: &[usize]) -> usize {
+        let mut freed = 0;
+        for &addr in addrs {
+            let page = self
+                .dealloc_range(addr, 1)
+                .ok()
+                .filter(|range| self.inner.dealloc(range.start));
+            let Some(Range { start: idx, .. }) = page else {
+                warn!("Try to free a page that is not allocated: {addr:#x}");
+                continue;
+            };
+            if self.track_allocations {
+                self.alloc_heads.remove(idx..idx + 1);
+            }
+            freed += 1;
+        }
+        self.used_pages -= freed;
+        freed
+    }
+
+    /// Allocate contiguous pages that lie entirely below `ceiling`.
+    ///
+    /// This is meant for buffers with a physical address limit, e.g. DMA for legacy
+    /// devices. Returns [`AllocError::NoMemory`] if nothing fits below the ceiling.
+    pub fn alloc_pages_below(
+        &mut self,
+        ceiling: usize,
+        num_pages: usize,
+        align_pow2: usize,
+    ) -> AllocResult<usize> {
+        // Check if the alignment is valid.
+        if num_pages == 0 || align_pow2 > MAX_ALIGN_1GB || !is_aligned(align_pow2, self.page_size) {
+            return Err(AllocError::InvalidParam);
+        }
+        let align_pow2 = align_pow2 / self.page_size;
+        if !align_pow2.is_power_of_two() {
+            return Err(AllocError::InvalidParam);
+        }
+        let align_log2 = align_pow2.trailing_zeros() as usize;
+
+        let limit = ceiling.saturating_sub(self.base) / self.page_size;
+        let start = find_contiguous_below(&self.inner, limit, num_pages, align_log2)
+            .ok_or(AllocError::NoMemory)?;
+        self.inner.remove(start..start + num_pages);
+
+        Ok(self.on_alloc(start, num_pages))
+    }
+
+    /// Iterate over the maximal runs of free pages as `(base, num_pages)`, in
+    /// ascending order.
+    ///
+    /// Runs span adjacent backed segments, and stop at unbacked ones, whose pages
+    /// are unavailable rather than free.
+    pub fn free_ranges(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        let cap = SegmentBitAllocCascade::<BitAlloc512, SIZE>::CAP;
+        let mut key = 0;
+        core::iter::from_fn(move || {
+            let start = self.inner.next(key)?;
+            let len = self.inner.free_run_len(start, cap - start);
+            key = start + len;
+            Some((self.page_addr(start), len))
+        })
+    }
+
+    /// Take a snapshot of the allocator's counters and layout.
+    pub fn stats(&self) -> AllocatorStats {
+        let mut largest_free = 0;
+        self.inner.for_each_run(|_, len, is_free| {
+            if is_free {
+                largest_free = largest_free.max(len);
+            }
+        });
+        AllocatorStats {
+            base: self.base,
+            page_size: self.page_size,
+            total_pages: self.total_pages,
+            used_pages: self.used_pages,
+            backed_segments: self.backed_segment_count(),
+            largest_free,
+        }
+    }
+
+    /// Whether the segment at `segment_idx` currently has physical backing.
+    pub fn is_segment_backed(&self, segment_idx: usize) -> bool {
+        segment_idx < SIZE && self.allocated_bitset.get(segment_idx)
+    }
+
+    /// Number of pages in backed segments that can hold allocations, i.e. that are
+    /// either allocated or available.
+    ///
+    /// Unlike [`PageAllocator::total_pages`], which stays the size of the initial
+    /// region, this follows segments being backed and freed. Permanently reserved
+    /// pages are not counted.
+    pub fn backed_pages(&self) -> usize {
+        self.used_pages + self.available_pages()
+    }
+
+    /// Number of segments that currently have physical backing.
+    pub fn backed_segment_count(&self) -> usize {
+        self.allocated_bitset.len()
     }
 
     pub fn get_allocated_bitset(&self) -> &Bitmap<SIZE> {
         &self.allocated_bitset
     }
 
+    /// Whether no page of the segment is allocated, so it can be freed.
     pub fn segment_is_free(&self, segment_idx: usize) -> bool {
         // Check if the segment is already free.
         if !self.allocated_bitset.get(segment_idx) {
             return true;
         }
 
-        self.inner.segment_is_free(segment_idx)
+        self.inner.segment_is_fully_available(segment_idx)
     }
 
     pub fn free_segment(&mut self, segment_idx: usize) {
@@ -145,13 +813,77 @@ where
         }
 
         // Remove the inner allocator for the segment.
-        let start = segment_idx * self.segment_granularity;
-        let end = start + self.segment_granularity;
-        self.inner.remove(start..end);
+        self.inner.remove(self.segment_bits(segment_idx));
 
         // Mark the segment as deallocated.
         self.allocated_bitset.set(segment_idx, false);
     }
+
+    /// Permanently exclude pages from allocation, e.g. firmware or MMIO windows.
+    ///
+    /// Free pages are taken out right away, pages in unbacked segments are kept out
+    /// when their segment is backed. Reserved pages are never freed again, not even
+    /// by [`Self::reset`]. They still count towards [`PageAllocator::total_pages`].
+    ///
+    /// Returns [`AllocError::MemoryOverlap`] if a page in the range is allocated.
+    pub fn reserve_permanent(&mut self, base: usize, num_pages: usize) -> AllocResult {
+        if num_pages == 0 || !is_aligned(base, self.page_size) {
+            return Err(AllocError::InvalidParam);
+        }
+        let start = self.bit_idx(base).ok_or(AllocError::InvalidParam)?;
+        let end = start + num_pages;
+        if end > SegmentBitAllocCascade::<BitAlloc512, SIZE>::CAP {
+            return Err(AllocError::InvalidParam);
+        }
+
+        let pages_per_segment = self.segment_granularity / self.page_size;
+        let overlaps = (start..end).any(|idx| {
+            self.is_segment_backed(idx / pages_per_segment)
+                && !self.inner.test(idx)
+                && !self.reserved.test(idx)
+        });
+        if overlaps {
+            return Err(AllocError::MemoryOverlap);
+        }
+
+        self.reserved.insert(start..end);
+        self.inner.remove(start..end);
+        Ok(())
+    }
+
+    /// Free every allocation at once, leaving all backed segments available except
+    /// for their permanently reserved pages.
+    pub fn reset(&mut self) {
+        let backed = self.allocated_bitset;
+        for segment_idx in &backed {
+            let bits = self.segment_bits(segment_idx);
+            self.inner.insert(bits.clone());
+            self.exclude_reserved(bits.clone());
+            self.alloc_heads.remove(bits);
+        }
+        self.used_pages = 0;
+    }
+
+    /// Free every backed segment none of whose pages is allocated.
+    ///
+    /// If `keep_initial` is true, the segment the allocator was initialized with is
+    /// kept even if free.
+    /// Returns the number of bytes reclaimed, i.e. how much physical memory the
+    /// caller can release.
+    pub fn trim(&mut self, keep_initial: bool) -> usize {
+        let mut freed_segments = 0;
+        let backed = self.allocated_bitset;
+        for segment_idx in &backed {
+            if keep_initial && segment_idx == self.initial_segment {
+                continue;
+            }
+            if self.segment_is_free(segment_idx) {
+                self.free_segment(segment_idx);
+                freed_segments += 1;
+            }
+        }
+        freed_segments * self.segment_granularity
+    }
 }
 
 impl<const SIZE: usize> BaseAllocator for SegmentBitmapPageAllocator<{ SIZE }>
@@ -159,8 +891,14 @@ where
     BitsImpl<{ SIZE }>: Bits,
 {
     /// Just init first segment.
+    ///
+    /// The page size must be set beforehand, use
+    /// [`SegmentBitmapPageAllocator::init_with_page_size`] which does both.
     fn init(&mut self, start: usize, size: usize) {
-        assert!(self.page_size.is_power_of_two());
+        assert!(
+            self.page_size.is_power_of_two(),
+            "page size is not set, call init_with_page_size instead of init"
+        );
 
         // Range for real:  [align_up(start, self.page_size), align_down(start + size, self.page_size))
         let end = align_down(start + size, self.page_size);
@@ -173,6 +911,10 @@ where
         // Range in bitmap: [start - self.base, start - self.base + total_pages * self.page_size)
         let start = start - self.base;
         let start_idx = start / self.page_size;
+        assert!(
+            start_idx + self.total_pages <= SegmentBitAllocCascade::<BitAlloc512, SIZE>::CAP,
+            "memory region exceeds the capacity of the allocator's {SIZE} segments"
+        );
 
         self.inner.insert(start_idx..start_idx + self.total_pages);
     }
@@ -195,20 +937,7 @@ where
         if !align_pow2.is_power_of_two() {
             return Err(AllocError::InvalidParam);
         }
-        let align_log2 = align_pow2.trailing_zeros() as usize;
-        match num_pages.cmp(&1) {
-            core::cmp::Ordering::Equal => self
-                .inner
-                .alloc()
-                .map(|idx| idx * self.page_size + self.base),
-            core::cmp::Ordering::Greater => self
-                .inner
-                .alloc_contiguous(None, num_pages, align_log2)
-                .map(|idx| idx * self.page_size + self.base),
-            _ => return Err(AllocError::InvalidParam),
-        }
-        .ok_or(AllocError::NoMemory)
-        .inspect(|_| self.used_pages += num_pages)
+        self.alloc_pages_log2(num_pages, align_pow2.trailing_zeros() as usize)
     }
 
     /// Allocate pages at a specific address.
@@ -233,13 +962,12 @@ where
         }
         let align_log2 = align_pow2.trailing_zeros() as usize;
 
-        let idx = (base - self.base) / self.page_size;
+        let idx = self.bit_idx(base).ok_or(AllocError::InvalidParam)?;
 
         self.inner
             .alloc_contiguous(Some(idx), num_pages, align_log2)
-            .map(|idx| idx * self.page_size + self.base)
             .ok_or(AllocError::NoMemory)
-            .inspect(|_| self.used_pages += num_pages)
+            .map(|idx| self.on_alloc(idx, num_pages))
     }
 
     fn dealloc_pages(&mut self, pos: usize, num_pages: usize) {
@@ -247,14 +975,20 @@ where
             is_aligned(pos, self.page_size),
             "pos must be aligned to self.page_size"
         );
-        if match num_pages.cmp(&1) {
-            core::cmp::Ordering::Equal => self.inner.dealloc((pos - self.base) / self.page_size),
-            core::cmp::Ordering::Greater => self
-                .inner
-                .dealloc_contiguous((pos - self.base) / self.page_size, num_pages),
-            _ => false,
-        } {
+        let freed = self
+            .dealloc_range(pos, num_pages)
+            .ok()
+            .filter(|range| match num_pages {
+                1 => self.inner.dealloc_checked(range.start).is_ok(),
+                _ => self.inner.dealloc_contiguous(range.start, num_pages),
+            });
+        if let Some(range) = freed {
             self.used_pages -= num_pages;
+            if self.track_allocations {
+                self.alloc_heads.remove(range);
+            }
+        } else {
+            warn!("Try to free pages that are not allocated: {pos:#x}, {num_pages}");
         }
     }
 
@@ -266,7 +1000,950 @@ where
         self.used_pages
     }
 
+    /// Free pages in backed segments only, so it does not necessarily add up with
+    /// [`Self::used_pages`] to [`Self::total_pages`].
     fn available_pages(&self) -> usize {
-        self.total_pages - self.used_pages
+        let cap = SegmentBitAllocCascade::<BitAlloc512, SIZE>::CAP;
+        self.inner.count_free_in(0..cap)
+    }
+
+    fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    /// The span covered by every segment, whether it is backed yet or not.
+    fn managed_range(&self) -> Range<usize> {
+        let cap = SegmentBitAllocCascade::<BitAlloc512, SIZE>::CAP;
+        self.base..self.base + cap * self.page_size
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use memory_addr::{PAGE_SIZE_1G, PAGE_SIZE_2M, PAGE_SIZE_4K};
+
+    use super::*;
+    use crate::test_util::display_in;
+
+    fn new_allocator<const SIZE: usize>() -> SegmentBitmapPageAllocator<SIZE>
+    where
+        BitsImpl<{ SIZE }>: Bits,
+    {
+        // SAFETY: all-zero is a valid, empty allocator state.
+        let mut allocator: SegmentBitmapPageAllocator<SIZE> = unsafe { core::mem::zeroed() };
+        allocator.init_with_page_size(PAGE_SIZE_4K, PAGE_SIZE_2M, 0, PAGE_SIZE_2M);
+        allocator
+    }
+
+    #[test]
+    fn alloc_pages_growing() {
+        let mut allocator = new_allocator::<2>();
+        assert_eq!(allocator.alloc_pages(512, PAGE_SIZE_4K), Ok(0));
+        assert_eq!(
+            allocator.alloc_pages(1, PAGE_SIZE_4K),
+            Err(AllocError::NoMemory)
+        );
+
+        // Provisioning is refused.
+        assert_eq!(
+            allocator.alloc_pages_growing(1, PAGE_SIZE_4K, |_| false),
+            Err(AllocError::NoMemory)
+        );
+
+        let mut grown = None;
+        let addr = allocator.alloc_pages_growing(1, PAGE_SIZE_4K, |base| {
+            grown = Some(base);
+            true
+        });
+        assert_eq!(grown, Some(PAGE_SIZE_2M));
+        assert_eq!(addr, Ok(PAGE_SIZE_2M));
+        assert_eq!(allocator.used_pages(), 513);
+
+        // No unbacked segment is left.
+        assert_eq!(
+            allocator.alloc_pages(511, PAGE_SIZE_4K),
+            Ok(PAGE_SIZE_2M + PAGE_SIZE_4K)
+        );
+        assert_eq!(
+            allocator.alloc_pages_growing(1, PAGE_SIZE_4K, |_| true),
+            Err(AllocError::NoMemory)
+        );
+    }
+
+    #[test]
+    fn increase_segment_at() {
+        let mut allocator = new_allocator::<2>();
+        assert_eq!(
+            allocator.increase_segment_at(0),
+            SegmentGrowResult::AlreadyBacked
+        );
+        assert_eq!(
+            allocator.increase_segment_at(PAGE_SIZE_2M),
+            SegmentGrowResult::Grown
+        );
+        assert_eq!(
+            allocator.increase_segment_at(PAGE_SIZE_2M),
+            SegmentGrowResult::AlreadyBacked
+        );
+        assert_eq!(
+            allocator.increase_segment_at(2 * PAGE_SIZE_2M),
+            SegmentGrowResult::OutOfRange
+        );
+        assert_eq!(allocator.alloc_pages(1024, PAGE_SIZE_4K), Ok(0));
+
+        // Below the allocator base.
+        let mut allocator: SegmentBitmapPageAllocator<2> = unsafe { core::mem::zeroed() };
+        allocator.init_with_page_size(PAGE_SIZE_4K, PAGE_SIZE_2M, MAX_ALIGN_1GB, PAGE_SIZE_2M);
+        assert_eq!(
+            allocator.increase_segment_at(0),
+            SegmentGrowResult::OutOfRange
+        );
+    }
+
+    #[test]
+    fn allocation_is_deterministic() {
+        fn run(allocator: &mut SegmentBitmapPageAllocator<2>) -> [usize; 6] {
+            let a = allocator.alloc_pages(3, PAGE_SIZE_4K).unwrap();
+            let b = allocator.alloc_pages(8, 8 * PAGE_SIZE_4K).unwrap();
+            let c = allocator.alloc_pages(1, PAGE_SIZE_4K).unwrap();
+            allocator.dealloc_pages(a, 3);
+            let d = allocator.alloc_pages(2, 2 * PAGE_SIZE_4K).unwrap();
+            allocator.dealloc_pages(c, 1);
+            let e = allocator.alloc_pages(1, PAGE_SIZE_4K).unwrap();
+            let f = allocator.alloc_pages(16, 16 * PAGE_SIZE_4K).unwrap();
+            [a, b, c, d, e, f]
+        }
+
+        let first = run(&mut new_allocator());
+        let second = run(&mut new_allocator());
+        assert_eq!(first, second);
+        assert_eq!(first[3], 0);
+    }
+
+    #[test]
+    fn freed_pages_are_reused() {
+        let mut allocator = new_allocator::<2>();
+        let pages: [usize; 8] =
+            core::array::from_fn(|_| allocator.alloc_pages(1, PAGE_SIZE_4K).unwrap());
+        let high_water = pages[7] + PAGE_SIZE_4K;
+
+        allocator.dealloc_pages(pages[2], 1);
+        assert_eq!(allocator.alloc_pages(1, PAGE_SIZE_4K), Ok(pages[2]));
+
+        allocator.dealloc_pages(pages[4], 1);
+        allocator.dealloc_pages(pages[5], 1);
+        assert_eq!(allocator.alloc_pages(2, PAGE_SIZE_4K), Ok(pages[4]));
+        assert_eq!(allocator.alloc_pages(1, PAGE_SIZE_4K), Ok(high_water));
+    }
+
+    #[test]
+    fn byte_counts_scale_with_page_size() {
+        let mut allocator: SegmentBitmapPageAllocator<2> = unsafe { core::mem::zeroed() };
+        allocator.init_with_page_size(PAGE_SIZE_2M, MAX_ALIGN_1GB, 0, MAX_ALIGN_1GB);
+        assert_eq!(PageAllocator::page_size(&allocator), PAGE_SIZE_2M);
+        assert_eq!(allocator.total_bytes(), MAX_ALIGN_1GB);
+        assert_eq!(allocator.used_bytes(), 0);
+
+        allocator.alloc_pages(3, PAGE_SIZE_2M).unwrap();
+        assert_eq!(allocator.used_bytes(), 3 * PAGE_SIZE_2M);
+        assert_eq!(
+            allocator.available_bytes(),
+            MAX_ALIGN_1GB - 3 * PAGE_SIZE_2M
+        );
+    }
+
+    #[test]
+    fn dealloc_pages_double_free() {
+        let mut allocator = new_allocator::<2>();
+        let addr = allocator.alloc_pages(1, PAGE_SIZE_4K).unwrap();
+        allocator.alloc_pages(1, PAGE_SIZE_4K).unwrap();
+        allocator.dealloc_pages(addr, 1);
+        assert_eq!(allocator.used_pages(), 1);
+        allocator.dealloc_pages(addr, 1);
+        assert_eq!(allocator.used_pages(), 1);
+    }
+
+    #[test]
+    fn dealloc_pages_exact() {
+        let mut allocator = new_allocator::<2>();
+        let addr = allocator.alloc_pages(4, PAGE_SIZE_4K).unwrap();
+        assert_eq!(
+            allocator.dealloc_pages_exact(addr, 4),
+            Err(AllocError::InvalidParam)
+        );
+        allocator.dealloc_pages(addr, 4);
+
+        allocator.set_track_allocations(true);
+        let a = allocator.alloc_pages(4, PAGE_SIZE_4K).unwrap();
+        let b = allocator.alloc_pages(2, PAGE_SIZE_4K).unwrap();
+        assert_eq!(b, a + 4 * PAGE_SIZE_4K);
+
+        // Partial ranges and ranges spanning two allocations are rejected.
+        for (pos, num_pages) in [(a, 2), (a, 3), (a + PAGE_SIZE_4K, 3), (a, 6), (b, 1)] {
+            assert_eq!(
+                allocator.dealloc_pages_exact(pos, num_pages),
+                Err(AllocError::NotAllocated)
+            );
+        }
+        assert_eq!(allocator.used_pages(), 6);
+
+        assert_eq!(allocator.dealloc_pages_exact(a, 4), Ok(()));
+        assert_eq!(allocator.used_pages(), 2);
+        assert_eq!(
+            allocator.dealloc_pages_exact(a, 4),
+            Err(AllocError::NotAllocated)
+        );
+        assert_eq!(allocator.dealloc_pages_exact(b, 2), Ok(()));
+        assert_eq!(allocator.used_pages(), 0);
+    }
+
+    #[test]
+    fn stats_snapshot() {
+        let mut allocator = new_allocator::<4>();
+        allocator.alloc_pages(10, PAGE_SIZE_4K).unwrap();
+        let addr = allocator.alloc_pages(100, PAGE_SIZE_4K).unwrap();
+        allocator.alloc_pages(1, PAGE_SIZE_4K).unwrap();
+        allocator.dealloc_pages(addr, 100);
+        let _ = allocator.increase_segment_at(2 * PAGE_SIZE_2M);
+
+        let stats = allocator.stats();
+        assert_eq!(stats.base, allocator.base());
+        assert_eq!(stats.page_size, allocator.page_size());
+        assert_eq!(stats.total_pages, allocator.total_pages());
+        assert_eq!(stats.used_pages, allocator.used_pages());
+        assert_eq!(stats.used_pages, 11);
+        assert_eq!(stats.backed_segments, 2);
+        assert_eq!(stats.largest_free, 512);
+
+        assert_eq!(
+            display_in(&mut [0; 128], stats),
+            "base 0x0, page size 0x1000, 11/512 pages used, 2 segments backed, largest free run 512 pages"
+        );
+    }
+
+    #[test]
+    fn segment_backing_state() {
+        let mut allocator = new_allocator::<4>();
+        assert!(allocator.is_segment_backed(0));
+        assert!(!allocator.is_segment_backed(1));
+        assert!(!allocator.is_segment_backed(4));
+        assert_eq!(allocator.backed_segment_count(), 1);
+
+        let _ = allocator.increase_segment_at(2 * PAGE_SIZE_2M);
+        assert!(allocator.is_segment_backed(2));
+        assert_eq!(allocator.backed_segment_count(), 2);
+
+        allocator.free_segment(2);
+        assert!(!allocator.is_segment_backed(2));
+        assert_eq!(allocator.backed_segment_count(), 1);
+    }
+
+    #[test]
+    fn alloc_pages_below() {
+        let mut allocator = new_allocator::<2>();
+        let _ = allocator.increase_segment_at(PAGE_SIZE_2M);
+        let ceiling = PAGE_SIZE_2M;
+
+        assert_eq!(allocator.alloc_pages(500, PAGE_SIZE_4K), Ok(0));
+        assert_eq!(
+            allocator.alloc_pages_below(ceiling, 12, PAGE_SIZE_4K),
+            Ok(500 * PAGE_SIZE_4K)
+        );
+        // The lower half is full, the upper half is excluded by the ceiling.
+        assert_eq!(
+            allocator.alloc_pages_below(ceiling, 1, PAGE_SIZE_4K),
+            Err(AllocError::NoMemory)
+        );
+        assert_eq!(allocator.used_pages(), 512);
+
+        allocator.dealloc_pages(0, 500);
+        assert_eq!(
+            allocator.alloc_pages_below(ceiling, 2, 2 * PAGE_SIZE_4K),
+            Ok(0)
+        );
+        assert_eq!(
+            allocator.alloc_pages_below(ceiling, 500, PAGE_SIZE_4K),
+            Err(AllocError::NoMemory)
+        );
+        // Without a ceiling the upper half is used.
+        assert_eq!(
+            allocator.alloc_pages_below(usize::MAX, 500, PAGE_SIZE_4K),
+            Ok(PAGE_SIZE_2M)
+        );
+        assert_eq!(
+            allocator.alloc_pages_below(ceiling, 0, PAGE_SIZE_4K),
+            Err(AllocError::InvalidParam)
+        );
+    }
+
+    #[test]
+    fn init_with_mapping_type() {
+        for mapping_type in [
+            GuestMappingType::CoarseGrainedSegmentation2M,
+            GuestMappingType::CoarseGrainedSegmentation1G,
+        ] {
+            let mut allocator: SegmentBitmapPageAllocator<2> = unsafe { core::mem::zeroed() };
+            allocator.init_with_mapping_type(mapping_type, 0, mapping_type.granularity_bytes());
+            assert_eq!(
+                allocator.segment_granularity(),
+                mapping_type.granularity_bytes()
+            );
+            assert_eq!(
+                GuestMappingType::try_from(allocator.segment_granularity()),
+                Ok(mapping_type)
+            );
+
+            // The second segment fits the inner bitmap.
+            let granularity = allocator.segment_granularity();
+            assert_eq!(
+                allocator.increase_segment_at(granularity),
+                SegmentGrowResult::Grown
+            );
+            let page_size = PageAllocator::page_size(&allocator);
+            assert_eq!(
+                allocator.alloc_pages_at(2 * granularity - page_size, 1, page_size),
+                Ok(2 * granularity - page_size)
+            );
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "a segment must span exactly 512 pages")]
+    fn init_with_mismatched_granularity() {
+        let mut allocator: SegmentBitmapPageAllocator<2> = unsafe { core::mem::zeroed() };
+        allocator.init_with_page_size(PAGE_SIZE_4K, PAGE_SIZE_1G, 0, PAGE_SIZE_2M);
+    }
+
+    #[test]
+    #[should_panic(expected = "One2OneMapping")]
+    fn init_with_one2one_mapping() {
+        let mut allocator: SegmentBitmapPageAllocator<2> = unsafe { core::mem::zeroed() };
+        allocator.init_with_mapping_type(GuestMappingType::One2OneMapping, 0, 0);
+    }
+
+    #[test]
+    fn alloc_pages_log2() {
+        let mut by_bytes = new_allocator::<2>();
+        let mut by_log2 = new_allocator::<2>();
+        for (num_pages, align_log2) in [(1, 0), (3, 0), (5, 2), (1, 4), (64, 6), (2, 0), (1, 8)] {
+            let addr = by_log2.alloc_pages_log2(num_pages, align_log2).unwrap();
+            assert!(is_aligned(addr, PAGE_SIZE_4K << align_log2), "{addr:#x}");
+            assert_eq!(
+                by_bytes.alloc_pages(num_pages, PAGE_SIZE_4K << align_log2),
+                Ok(addr)
+            );
+        }
+        assert_eq!(by_bytes.used_pages(), by_log2.used_pages());
+
+        // 1GB is the largest alignment.
+        assert_eq!(
+            by_log2.alloc_pages_log2(1, 19),
+            Err(AllocError::InvalidParam)
+        );
+        assert_eq!(
+            by_log2.alloc_pages_log2(0, 0),
+            Err(AllocError::InvalidParam)
+        );
+    }
+
+    #[test]
+    fn init_unaligned_start() {
+        let start = PAGE_SIZE_2M + 100 * PAGE_SIZE_4K;
+        let mut allocator: SegmentBitmapPageAllocator<4> = unsafe { core::mem::zeroed() };
+        allocator.init_with_page_size(
+            PAGE_SIZE_4K,
+            PAGE_SIZE_2M,
+            start,
+            PAGE_SIZE_2M - 100 * PAGE_SIZE_4K,
+        );
+        assert_eq!(allocator.base(), 0);
+        assert_eq!(allocator.total_pages(), 412);
+        assert!(!allocator.is_segment_backed(0));
+        assert!(allocator.is_segment_backed(1));
+        assert_eq!(
+            allocator.increase_segment_at(PAGE_SIZE_2M),
+            SegmentGrowResult::AlreadyBacked
+        );
+
+        assert_eq!(allocator.alloc_pages(1, PAGE_SIZE_4K), Ok(start));
+        assert_eq!(
+            allocator.alloc_pages(2, 64 * PAGE_SIZE_4K),
+            Ok(PAGE_SIZE_2M + 128 * PAGE_SIZE_4K)
+        );
+        while let Ok(addr) = allocator.alloc_pages(1, PAGE_SIZE_4K) {
+            assert!(addr >= start);
+        }
+        assert_eq!(allocator.used_pages(), 412);
+
+        // The pages below `start` are not memory, a reset must not free them.
+        allocator.reset();
+        assert_eq!(
+            allocator.alloc_pages_at(PAGE_SIZE_2M, 1, PAGE_SIZE_4K),
+            Err(AllocError::NoMemory)
+        );
+        assert_eq!(allocator.free_ranges().map(|(_, n)| n).sum::<usize>(), 412);
+    }
+
+    #[test]
+    fn page_addr_bit_idx() {
+        let mut allocator: SegmentBitmapPageAllocator<2> = unsafe { core::mem::zeroed() };
+        let start = PAGE_SIZE_1G + PAGE_SIZE_2M;
+        allocator.init_with_page_size(PAGE_SIZE_4K, PAGE_SIZE_2M, start, PAGE_SIZE_2M);
+
+        for bit_idx in [0, 1, 511, 512, 1023] {
+            let addr = allocator.page_addr(bit_idx);
+            assert_eq!(allocator.bit_idx(addr), Some(bit_idx));
+        }
+        assert_eq!(allocator.page_addr(512), start);
+
+        // Below the base, past the last segment, or not page aligned.
+        assert_eq!(allocator.bit_idx(PAGE_SIZE_1G - PAGE_SIZE_4K), None);
+        assert_eq!(allocator.bit_idx(PAGE_SIZE_1G + 2 * PAGE_SIZE_2M), None);
+        assert_eq!(allocator.bit_idx(start + 1), None);
+        assert_eq!(
+            allocator.alloc_pages_at(PAGE_SIZE_4K, 1, PAGE_SIZE_4K),
+            Err(AllocError::InvalidParam)
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "call init_with_page_size")]
+    fn init_without_page_size() {
+        let mut allocator: SegmentBitmapPageAllocator<2> = unsafe { core::mem::zeroed() };
+        allocator.init(0, PAGE_SIZE_2M);
+    }
+
+    #[test]
+    fn trim() {
+        let mut allocator = new_allocator::<4>();
+        for segment_idx in 1..4 {
+            let _ = allocator.increase_segment_at(segment_idx * PAGE_SIZE_2M);
+        }
+        // Segment 2 stays partially used, segment 3 is used then freed again.
+        let addr = allocator
+            .alloc_pages_at(2 * PAGE_SIZE_2M + PAGE_SIZE_4K, 1, PAGE_SIZE_4K)
+            .unwrap();
+        let other = allocator
+            .alloc_pages_at(3 * PAGE_SIZE_2M, 4, PAGE_SIZE_4K)
+            .unwrap();
+        allocator.dealloc_pages(other, 4);
+
+        assert_eq!(allocator.trim(true), 2 * PAGE_SIZE_2M);
+        assert!(allocator.is_segment_backed(0));
+        assert!(!allocator.is_segment_backed(1));
+        assert!(allocator.is_segment_backed(2));
+        assert!(!allocator.is_segment_backed(3));
+        assert_eq!(allocator.trim(true), 0);
+
+        assert_eq!(allocator.trim(false), PAGE_SIZE_2M);
+        assert!(!allocator.is_segment_backed(0));
+        allocator.dealloc_pages(addr, 1);
+        assert_eq!(allocator.trim(false), PAGE_SIZE_2M);
+        assert_eq!(allocator.backed_segment_count(), 0);
+
+        // The initial segment need not be the one at the base.
+        let mut allocator: SegmentBitmapPageAllocator<4> = unsafe { core::mem::zeroed() };
+        allocator.init_with_page_size(PAGE_SIZE_4K, PAGE_SIZE_2M, PAGE_SIZE_2M, PAGE_SIZE_2M);
+        let _ = allocator.increase_segment_at(0);
+        assert_eq!(allocator.trim(true), PAGE_SIZE_2M);
+        assert!(!allocator.is_segment_backed(0));
+        assert!(allocator.is_segment_backed(1));
+    }
+
+    #[test]
+    fn frame_range() {
+        let mut allocator = new_allocator::<2>();
+        allocator.alloc_pages(1, PAGE_SIZE_4K).unwrap();
+
+        let range = allocator.alloc_frame_range(16, PAGE_SIZE_4K).unwrap();
+        assert_eq!(range.base, PAGE_SIZE_4K);
+        assert_eq!(range.bytes(), 16 * PAGE_SIZE_4K);
+        assert!(!range.contains(range.base - 1));
+        assert!(range.contains(range.base));
+        assert!(range.contains(range.base + range.bytes() - 1));
+        assert!(!range.contains(range.base + range.bytes()));
+        assert_eq!(allocator.used_pages(), 17);
+
+        allocator.dealloc_frame_range(range);
+        assert_eq!(allocator.used_pages(), 1);
+        assert_eq!(
+            allocator.alloc_frame_range(16, PAGE_SIZE_4K),
+            Ok(FrameRange {
+                base: PAGE_SIZE_4K,
+                num_pages: 16,
+                page_size: PAGE_SIZE_4K,
+            })
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "exceeds the capacity")]
+    fn init_beyond_capacity() {
+        let mut allocator: SegmentBitmapPageAllocator<2> = unsafe { core::mem::zeroed() };
+        allocator.init_with_page_size(PAGE_SIZE_4K, PAGE_SIZE_2M, PAGE_SIZE_2M, 2 * PAGE_SIZE_2M);
+    }
+
+    #[test]
+    fn dealloc_pages_scattered() {
+        let mut allocator = new_allocator::<2>();
+        allocator.set_track_allocations(true);
+        let pages: [usize; 4] =
+            core::array::from_fn(|_| allocator.alloc_pages(1, PAGE_SIZE_4K).unwrap());
+
+        let freed = allocator.dealloc_pages_scattered(&[
+            pages[0],
+            pages[2],
+            pages[2],
+            16 * PAGE_SIZE_4K,
+            PAGE_SIZE_2M + 1,
+            // A page of the unbacked segment 1.
+            PAGE_SIZE_2M,
+        ]);
+        assert_eq!(freed, 2);
+        assert_eq!(allocator.used_pages(), 2);
+        assert_eq!(allocator.available_pages(), 510);
+        assert_eq!(allocator.dealloc_pages_exact(pages[1], 1), Ok(()));
+        assert_eq!(
+            allocator.dealloc_pages_exact(pages[2], 1),
+            Err(AllocError::NotAllocated)
+        );
+        assert_eq!(allocator.alloc_pages(1, PAGE_SIZE_4K), Ok(pages[0]));
+    }
+
+    #[test]
+    fn compaction_plan() {
+        let mut allocator = new_allocator::<2>();
+        allocator.set_track_allocations(true);
+        let sizes = [4, 8, 2, 16, 3];
+        let addrs = sizes.map(|num_pages| allocator.alloc_pages(num_pages, PAGE_SIZE_4K).unwrap());
+        allocator.dealloc_pages(addrs[0], sizes[0]);
+        allocator.dealloc_pages(addrs[2], sizes[2]);
+
+        let mut plan = [(0, 0); 4];
+        assert_eq!(allocator.compaction_plan(&mut plan[..1]), 3);
+        assert_eq!(plan[0], (addrs[1], 0));
+        assert_eq!(allocator.compaction_plan(&mut plan), 3);
+        assert_eq!(
+            plan[..3],
+            [
+                (addrs[1], 0),
+                (addrs[3], 8 * PAGE_SIZE_4K),
+                (addrs[4], 24 * PAGE_SIZE_4K)
+            ]
+        );
+
+        // Realize the plan, which leaves a single free run.
+        for (&(from, to), num_pages) in plan[..3].iter().zip([sizes[1], sizes[3], sizes[4]]) {
+            allocator.dealloc_pages_exact(from, num_pages).unwrap();
+            assert_eq!(
+                allocator.alloc_pages_at(to, num_pages, PAGE_SIZE_4K),
+                Ok(to)
+            );
+        }
+        let stats = allocator.stats();
+        assert_eq!(stats.largest_free, stats.total_pages - stats.used_pages);
+        assert_eq!(allocator.compaction_plan(&mut plan), 0);
+    }
+
+    #[test]
+    fn compaction_plan_stops_at_untracked_pages() {
+        let mut allocator = new_allocator::<2>();
+        let _ = allocator.increase_segment_at(PAGE_SIZE_2M);
+        let untracked = allocator.alloc_pages(1, PAGE_SIZE_4K).unwrap();
+        allocator.set_track_allocations(true);
+        let first = allocator.alloc_pages(2, PAGE_SIZE_4K).unwrap();
+        // The second allocation spans both segments.
+        let second = allocator
+            .alloc_pages_at(PAGE_SIZE_2M - PAGE_SIZE_4K, 2, PAGE_SIZE_4K)
+            .unwrap();
+        allocator.dealloc_pages(first, 2);
+
+        let mut plan = [(0, 0); 2];
+        assert_eq!(allocator.compaction_plan(&mut plan), 1);
+        assert_eq!(plan[0], (second, untracked + PAGE_SIZE_4K));
+    }
+
+    #[test]
+    fn new_in_place() {
+        let mut memory = core::mem::MaybeUninit::<SegmentBitmapPageAllocator<2>>::uninit();
+        unsafe { memory.as_mut_ptr().write_bytes(0xff, 1) };
+        let allocator = unsafe {
+            SegmentBitmapPageAllocator::new_in_place(
+                memory.as_mut_ptr(),
+                PAGE_SIZE_4K,
+                PAGE_SIZE_2M,
+                PAGE_SIZE_2M,
+                PAGE_SIZE_2M,
+            )
+        };
+
+        assert_eq!(allocator.used_pages(), 0);
+        assert_eq!(allocator.total_pages(), 512);
+        assert_eq!(allocator.backed_segment_count(), 1);
+        assert_eq!(allocator.alloc_pages(4, PAGE_SIZE_4K), Ok(PAGE_SIZE_2M));
+        assert_eq!(allocator.increase_segment_at(0), SegmentGrowResult::Grown);
+    }
+
+    #[test]
+    fn managed_range() {
+        fn owns(allocator: &impl PageAllocator, addr: usize) -> bool {
+            allocator.managed_range().contains(&addr)
+        }
+
+        let mut allocator: SegmentBitmapPageAllocator<2> = unsafe { core::mem::zeroed() };
+        allocator.init_with_page_size(PAGE_SIZE_4K, PAGE_SIZE_2M, PAGE_SIZE_1G, PAGE_SIZE_2M);
+        assert_eq!(
+            allocator.managed_range(),
+            PAGE_SIZE_1G..PAGE_SIZE_1G + 2 * PAGE_SIZE_2M
+        );
+
+        let addr = allocator.alloc_pages(1, PAGE_SIZE_4K).unwrap();
+        assert!(owns(&allocator, addr));
+        assert!(owns(&allocator, PAGE_SIZE_1G + 2 * PAGE_SIZE_2M - 1));
+        assert!(!owns(&allocator, PAGE_SIZE_1G - 1));
+        assert!(!owns(&allocator, PAGE_SIZE_1G + 2 * PAGE_SIZE_2M));
+    }
+
+    #[test]
+    fn segment_is_free() {
+        let mut allocator = new_allocator::<4>();
+        let _ = allocator.increase_segment_at(PAGE_SIZE_2M);
+        let _ = allocator.increase_segment_at(2 * PAGE_SIZE_2M);
+        // Segment 0 is fully allocated, 1 partially, 2 not at all, 3 is unbacked.
+        allocator.alloc_pages(512, PAGE_SIZE_4K).unwrap();
+        allocator.alloc_pages(1, PAGE_SIZE_4K).unwrap();
+
+        assert!(!allocator.segment_is_free(0));
+        assert!(!allocator.segment_is_free(1));
+        assert!(allocator.segment_is_free(2));
+        assert!(allocator.segment_is_free(3));
+    }
+
+    #[test]
+    fn free_ranges() {
+        let mut allocator = new_allocator::<4>();
+        let _ = allocator.increase_segment_at(PAGE_SIZE_2M);
+        let _ = allocator.increase_segment_at(3 * PAGE_SIZE_2M);
+        allocator.alloc_pages(2, PAGE_SIZE_4K).unwrap();
+        allocator
+            .alloc_pages_at(3 * PAGE_SIZE_2M + 10 * PAGE_SIZE_4K, 1, PAGE_SIZE_4K)
+            .unwrap();
+
+        // Segments 0 and 1 are coalesced, segment 2 is unbacked.
+        let mut ranges = allocator.free_ranges();
+        assert_eq!(ranges.next(), Some((2 * PAGE_SIZE_4K, 1022)));
+        assert_eq!(ranges.next(), Some((3 * PAGE_SIZE_2M, 10)));
+        assert_eq!(
+            ranges.next(),
+            Some((3 * PAGE_SIZE_2M + 11 * PAGE_SIZE_4K, 501))
+        );
+        assert_eq!(ranges.next(), None);
+    }
+
+    #[test]
+    fn alloc_pages_at_growing() {
+        let mut allocator = new_allocator::<4>();
+        let base = PAGE_SIZE_2M - 2 * PAGE_SIZE_4K;
+        let mut grown = [0; 4];
+        let mut count = 0;
+
+        // Straddles the backed segment 0 and the unbacked segment 1.
+        assert_eq!(
+            allocator.alloc_pages_at_growing(base, 4, PAGE_SIZE_4K, |segment_base| {
+                grown[count] = segment_base;
+                count += 1;
+                true
+            }),
+            Ok(base)
+        );
+        assert_eq!(grown[..count], [PAGE_SIZE_2M]);
+        assert!(allocator.is_segment_backed(1));
+        assert_eq!(allocator.used_pages(), 4);
+
+        // The placement overlaps allocated pages, so segment 2 is freed again.
+        assert_eq!(
+            allocator.alloc_pages_at_growing(
+                PAGE_SIZE_2M + PAGE_SIZE_4K,
+                PAGE_SIZE_2M / PAGE_SIZE_4K,
+                PAGE_SIZE_4K,
+                |_| true
+            ),
+            Err(AllocError::NoMemory)
+        );
+        assert!(!allocator.is_segment_backed(2));
+
+        // Growing segment 3 is refused, so segment 2 is freed again.
+        assert_eq!(
+            allocator.alloc_pages_at_growing(
+                2 * PAGE_SIZE_2M + PAGE_SIZE_4K,
+                PAGE_SIZE_2M / PAGE_SIZE_4K,
+                PAGE_SIZE_4K,
+                |segment_base| segment_base != 3 * PAGE_SIZE_2M
+            ),
+            Err(AllocError::NoMemory)
+        );
+        assert!(!allocator.is_segment_backed(2));
+        assert!(!allocator.is_segment_backed(3));
+
+        // Past the last segment.
+        assert_eq!(
+            allocator.alloc_pages_at_growing(
+                4 * PAGE_SIZE_2M - PAGE_SIZE_4K,
+                2,
+                PAGE_SIZE_4K,
+                |_| true
+            ),
+            Err(AllocError::NoMemory)
+        );
+        assert!(!allocator.is_segment_backed(3));
+        assert_eq!(
+            allocator.alloc_pages_at_growing(2 * PAGE_SIZE_4K, usize::MAX, PAGE_SIZE_4K, |_| true),
+            Err(AllocError::InvalidParam)
+        );
+        assert_eq!(
+            allocator.alloc_pages_at_growing(PAGE_SIZE_4K, 0, PAGE_SIZE_4K, |_| true),
+            Err(AllocError::InvalidParam)
+        );
+    }
+
+    #[test]
+    fn reserve_permanent() {
+        let mut allocator = new_allocator::<4>();
+        let addr = allocator.alloc_pages(2, PAGE_SIZE_4K).unwrap();
+        assert_eq!(
+            allocator.reserve_permanent(addr, 1),
+            Err(AllocError::MemoryOverlap)
+        );
+        assert_eq!(
+            allocator.reserve_permanent(8 * PAGE_SIZE_4K, 0),
+            Err(AllocError::InvalidParam)
+        );
+
+        // Free pages in segment 0 and pages in the unbacked segment 1.
+        assert_eq!(allocator.reserve_permanent(8 * PAGE_SIZE_4K, 4), Ok(()));
+        assert_eq!(allocator.reserve_permanent(PAGE_SIZE_2M, 1), Ok(()));
+        assert_eq!(
+            allocator.alloc_pages_at(8 * PAGE_SIZE_4K, 1, PAGE_SIZE_4K),
+            Err(AllocError::NoMemory)
+        );
+        // Freeing a reserved page is rejected.
+        allocator.dealloc_pages(8 * PAGE_SIZE_4K, 1);
+        assert_eq!(allocator.dealloc_pages_scattered(&[9 * PAGE_SIZE_4K]), 0);
+        assert_eq!(allocator.used_pages(), 2);
+
+        allocator.reset();
+        assert_eq!(allocator.used_pages(), 0);
+        assert_eq!(allocator.alloc_pages_at(addr, 2, PAGE_SIZE_4K), Ok(addr));
+        for page in 8..12 {
+            assert_eq!(
+                allocator.alloc_pages_at(page * PAGE_SIZE_4K, 1, PAGE_SIZE_4K),
+                Err(AllocError::NoMemory)
+            );
+        }
+        assert_eq!(
+            allocator.alloc_pages_at(12 * PAGE_SIZE_4K, 1, PAGE_SIZE_4K),
+            Ok(12 * PAGE_SIZE_4K)
+        );
+
+        // Backing segment 1 keeps its reserved page out, also across a reset.
+        assert_eq!(
+            allocator.increase_segment_at(PAGE_SIZE_2M),
+            SegmentGrowResult::Grown
+        );
+        allocator.reset();
+        assert_eq!(
+            allocator.alloc_pages_at(PAGE_SIZE_2M, 1, PAGE_SIZE_4K),
+            Err(AllocError::NoMemory)
+        );
+        assert_eq!(
+            allocator.alloc_pages_at(PAGE_SIZE_2M + PAGE_SIZE_4K, 1, PAGE_SIZE_4K),
+            Ok(PAGE_SIZE_2M + PAGE_SIZE_4K)
+        );
+    }
+
+    #[test]
+    fn dealloc_pages_aligned() {
+        let mut allocator = new_allocator::<2>();
+        let align = 16 * PAGE_SIZE_4K;
+        let first = allocator.alloc_pages(16, align).unwrap();
+        let second = allocator.alloc_pages(16, align).unwrap();
+
+        assert_eq!(
+            allocator.dealloc_pages_aligned(second + 8 * PAGE_SIZE_4K, 8, align),
+            Err(AllocError::InvalidParam)
+        );
+        assert_eq!(allocator.used_pages(), 32);
+        assert_eq!(allocator.dealloc_pages_aligned(second, 16, align), Ok(()));
+        assert_eq!(
+            allocator.dealloc_pages_aligned(second, 16, align),
+            Err(AllocError::NotAllocated)
+        );
+        assert_eq!(allocator.dealloc_pages_aligned(first, 16, align), Ok(()));
+        assert_eq!(allocator.used_pages(), 0);
+        assert_eq!(allocator.alloc_pages(32, 2 * align), Ok(first));
+    }
+
+    #[test]
+    fn available_pages_follow_backing() {
+        let mut allocator = new_allocator::<4>();
+        assert_eq!(allocator.available_pages(), 512);
+        assert_eq!(
+            allocator.increase_segment_at(PAGE_SIZE_2M),
+            SegmentGrowResult::Grown
+        );
+        assert_eq!(allocator.available_pages(), 1024);
+        assert_eq!(allocator.backed_pages(), 1024);
+
+        allocator.alloc_pages(2, PAGE_SIZE_4K).unwrap();
+        assert_eq!(allocator.available_pages(), 1022);
+        assert_eq!(allocator.trim(false), PAGE_SIZE_2M);
+        assert_eq!(allocator.available_pages(), 510);
+        assert_eq!(allocator.backed_pages(), 512);
+        assert_eq!(allocator.total_pages(), 512);
+
+        assert_eq!(
+            allocator.reserve_permanent(PAGE_SIZE_2M - PAGE_SIZE_4K, 1),
+            Ok(())
+        );
+        assert_eq!(allocator.available_pages(), 509);
+        assert_eq!(allocator.backed_pages(), 511);
+    }
+
+    #[test]
+    fn reserved_pages_bound_allocations() {
+        let mut allocator = new_allocator::<4>();
+        allocator.set_track_allocations(true);
+        let hole = allocator.alloc_pages(2, PAGE_SIZE_4K).unwrap();
+        let addr = allocator.alloc_pages(2, PAGE_SIZE_4K).unwrap();
+        assert_eq!(
+            allocator.reserve_permanent(addr + 2 * PAGE_SIZE_4K, 1),
+            Ok(())
+        );
+        allocator.dealloc_pages(hole, 2);
+
+        // The reserved page is not part of the allocation, and never moves.
+        let mut moves = [(0, 0); 2];
+        assert_eq!(allocator.compaction_plan(&mut moves), 1);
+        assert_eq!(moves[0], (addr, hole));
+        assert_eq!(allocator.dealloc_pages_exact(addr, 2), Ok(()));
+        assert_eq!(allocator.compaction_plan(&mut moves), 0);
+    }
+
+    #[test]
+    fn dealloc_pages_exact_at_region_end() {
+        // The rest of the segment after the region is reserved by `init`.
+        let mut allocator: SegmentBitmapPageAllocator<4> = unsafe { core::mem::zeroed() };
+        allocator.init_with_page_size(PAGE_SIZE_4K, PAGE_SIZE_2M, 0, 16 * PAGE_SIZE_4K);
+        allocator.set_track_allocations(true);
+        let addr = allocator.alloc_pages(16, PAGE_SIZE_4K).unwrap();
+        let mut moves = [(0, 0); 1];
+        assert_eq!(allocator.compaction_plan(&mut moves), 0);
+        assert_eq!(allocator.dealloc_pages_exact(addr, 16), Ok(()));
+        assert_eq!(allocator.used_pages(), 0);
+    }
+
+    #[test]
+    fn dealloc_pages_exact_at_segment_end() {
+        // Segment 1, right after the allocation, is not backed.
+        let mut allocator = new_allocator::<4>();
+        allocator.set_track_allocations(true);
+        let addr = PAGE_SIZE_2M - 4 * PAGE_SIZE_4K;
+        assert_eq!(allocator.alloc_pages_at(addr, 4, PAGE_SIZE_4K), Ok(addr));
+        // Too long, running into the unbacked segment.
+        assert_eq!(
+            allocator.dealloc_pages_exact(addr, 10),
+            Err(AllocError::NotAllocated)
+        );
+        assert_eq!(allocator.used_pages(), 4);
+        assert_eq!(allocator.dealloc_pages_exact(addr, 4), Ok(()));
+        assert_eq!(allocator.used_pages(), 0);
+        assert_eq!(
+            allocator.alloc_pages_at(PAGE_SIZE_2M, 1, PAGE_SIZE_4K),
+            Err(AllocError::NoMemory)
+        );
+    }
+
+    #[test]
+    fn verify() {
+        let mut allocator = new_allocator::<2>();
+        allocator.alloc_pages(4, PAGE_SIZE_4K).unwrap();
+        assert!(allocator.verify());
+
+        // Each of these would later index past the inner bitmap, or underflow.
+        let broken: [fn(&mut SegmentBitmapPageAllocator<2>); 5] = [
+            |a| a.segment_granularity = PAGE_SIZE_1G,
+            |a| a.page_size = 3 * PAGE_SIZE_4K,
+            |a| a.base = PAGE_SIZE_2M,
+            |a| a.total_pages = 2 * 512 + 1,
+            |a| a.used_pages = 513,
+        ];
+        for corrupt in broken {
+            let mut allocator = new_allocator::<2>();
+            corrupt(&mut allocator);
+            assert!(!allocator.verify());
+        }
+    }
+
+    #[test]
+    fn init_spanning_segments() {
+        let mut allocator: SegmentBitmapPageAllocator<4> = unsafe { core::mem::zeroed() };
+        allocator.init_with_page_size(PAGE_SIZE_4K, PAGE_SIZE_2M, 0, 2 * PAGE_SIZE_2M);
+        assert!(allocator.is_segment_backed(0));
+        assert!(allocator.is_segment_backed(1));
+        assert!(!allocator.is_segment_backed(2));
+        assert_eq!(allocator.alloc_pages(1024, PAGE_SIZE_4K), Ok(0));
+        assert!(allocator.verify());
+
+        // Growing picks the first segment past the region.
+        let mut grown = 0;
+        assert_eq!(
+            allocator.alloc_pages_growing(1, PAGE_SIZE_4K, |segment_base| {
+                grown = segment_base;
+                true
+            }),
+            Ok(2 * PAGE_SIZE_2M)
+        );
+        assert_eq!(grown, 2 * PAGE_SIZE_2M);
+        assert_eq!(allocator.used_pages(), 1025);
+        assert_eq!(
+            allocator.dealloc_pages_scattered(&[PAGE_SIZE_2M + PAGE_SIZE_4K]),
+            1
+        );
+
+        // A region ending inside a segment reserves the rest of it.
+        let mut allocator: SegmentBitmapPageAllocator<4> = unsafe { core::mem::zeroed() };
+        allocator.init_with_page_size(PAGE_SIZE_4K, PAGE_SIZE_2M, 0, PAGE_SIZE_2M + PAGE_SIZE_4K);
+        assert_eq!(allocator.backed_segment_count(), 2);
+        assert_eq!(allocator.available_pages(), 513);
+        allocator.reset();
+        assert_eq!(allocator.available_pages(), 513);
+    }
+
+    #[test]
+    fn dealloc_unbacked_pages() {
+        let mut allocator = new_allocator::<2>();
+        allocator.alloc_pages(100, PAGE_SIZE_4K).unwrap();
+        let align = 16 * PAGE_SIZE_4K;
+
+        // Segment 1 is not backed, its pages read as allocated but are not.
+        assert_eq!(
+            allocator.dealloc_pages_aligned(PAGE_SIZE_2M, 16, align),
+            Err(AllocError::NotAllocated)
+        );
+        allocator.dealloc_pages(PAGE_SIZE_2M + 2 * align, 4);
+        allocator.dealloc_pages(PAGE_SIZE_2M - PAGE_SIZE_4K, 2);
+        assert_eq!(allocator.used_pages(), 100);
+        assert_eq!(
+            allocator.alloc_pages_at(PAGE_SIZE_2M, 1, PAGE_SIZE_4K),
+            Err(AllocError::NoMemory)
+        );
+
+        // Huge counts don't overflow.
+        assert_eq!(
+            allocator.dealloc_pages_aligned(align, usize::MAX, align),
+            Err(AllocError::InvalidParam)
+        );
+        allocator.dealloc_pages(PAGE_SIZE_4K, usize::MAX);
+        assert_eq!(allocator.used_pages(), 100);
     }
 }
@@ -0,0 +1,134 @@
+//! Owned stand-ins for the region mappings, so that region logic can be exercised
+//! without a hypervisor mapping the regions at their fixed addresses.
+//!
+//! Requires a global allocator, which test binaries get from `std`.
+
+extern crate alloc;
+
+use alloc::alloc::{Layout, alloc_zeroed, dealloc, handle_alloc_error};
+use core::ptr::NonNull;
+
+use memory_addr::PAGE_SIZE_4K;
+
+use crate::structs::{PROCESS_INNER_REGION_SIZE, ProcessInnerRegion};
+
+/// A zeroed, page-aligned buffer the size of the process inner region, at which a
+/// [`ProcessInnerRegion`] can be placed, with its stack right after it.
+pub struct RegionHarness {
+    ptr: NonNull<u8>,
+}
+
+impl RegionHarness {
+    fn layout() -> Layout {
+        Layout::from_size_align(PROCESS_INNER_REGION_SIZE, PAGE_SIZE_4K).unwrap()
+    }
+
+    pub fn new() -> Self {
+        // SAFETY: the layout has a non-zero size.
+        let ptr = unsafe { alloc_zeroed(Self::layout()) };
+        let ptr = NonNull::new(ptr).unwrap_or_else(|| handle_alloc_error(Self::layout()));
+        Self { ptr }
+    }
+
+    /// Base address of the buffer, standing in for `PROCESS_INNER_REGION_BASE_VA`.
+    pub fn base(&self) -> usize {
+        self.ptr.as_ptr() as usize
+    }
+
+    pub fn process_inner_region(&self) -> &ProcessInnerRegion {
+        // SAFETY: the buffer is aligned and large enough, and all-zero is a valid
+        // `ProcessInnerRegion`, which is only ever mutated through `&mut self`.
+        unsafe { self.ptr.cast().as_ref() }
+    }
+
+    pub fn process_inner_region_mut(&mut self) -> &mut ProcessInnerRegion {
+        // SAFETY: see `process_inner_region`, and `&mut self` guarantees exclusivity.
+        unsafe { self.ptr.cast().as_mut() }
+    }
+}
+
+impl Default for RegionHarness {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for RegionHarness {
+    fn drop(&mut self) {
+        // SAFETY: `ptr` was allocated in `new` with the same layout.
+        unsafe { dealloc(self.ptr.as_ptr(), Self::layout()) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use memory_addr::PAGE_SIZE_2M;
+
+    use super::*;
+    use crate::addrs::{GUEST_MEM_REGION_BASE_PA, GuestMappingType};
+    use crate::bitmap_allocator::PageAllocator;
+    use crate::structs::MMFrameAllocator;
+
+    #[test]
+    fn process_inner_region() {
+        let mut harness = RegionHarness::new();
+        let region = harness.process_inner_region_mut();
+        region.init(7, true, GuestMappingType::CoarseGrainedSegmentation2M);
+
+        let addr = region
+            .mm_frame_allocator
+            .alloc_pages(4, PAGE_SIZE_4K)
+            .unwrap();
+        assert_eq!(addr, GUEST_MEM_REGION_BASE_PA);
+        let tls = region.alloc_tls(PAGE_SIZE_4K).unwrap();
+        assert_eq!(tls % PAGE_SIZE_4K, 0);
+
+        let region = harness.process_inner_region();
+        assert_eq!(region.process_id, 7);
+        assert_eq!(region.mm_frame_allocator.used_pages(), 5);
+        // The stack lies within the buffer, right after the structure.
+        assert_eq!(region.stack_top(), harness.base() + PAGE_SIZE_2M - 8);
+        assert_eq!(
+            region.stack_top() + 8 - ProcessInnerRegion::STACK_SIZE,
+            harness.base() + size_of::<ProcessInnerRegion>()
+        );
+    }
+
+    #[test]
+    fn allocator_from_raw_state() {
+        let mut harness = RegionHarness::new();
+        let ptr = (harness.base() + core::mem::offset_of!(ProcessInnerRegion, mm_frame_allocator))
+            as *mut MMFrameAllocator;
+
+        // SAFETY: `ptr` points into the harness buffer, where all-zero is a valid
+        // allocator state, and no other reference to it is alive.
+        let allocator = unsafe { MMFrameAllocator::from_raw_state(ptr) };
+        assert!(!allocator.verify());
+
+        let region = harness.process_inner_region_mut();
+        region.init(1, true, GuestMappingType::CoarseGrainedSegmentation2M);
+        let addr = region
+            .mm_frame_allocator
+            .alloc_pages(2, PAGE_SIZE_4K)
+            .unwrap();
+
+        // SAFETY: as above, the region borrow has ended.
+        let allocator = unsafe { MMFrameAllocator::from_raw_state(ptr) };
+        assert!(allocator.verify());
+        assert_eq!(allocator.used_pages(), 2);
+        allocator.dealloc_pages(addr, 2);
+        assert_eq!(
+            harness
+                .process_inner_region()
+                .mm_frame_allocator
+                .used_pages(),
+            0
+        );
+
+        // Garbage, though still a valid bit pattern for every field.
+        // SAFETY: `ptr` is valid for the whole allocator, and no reference to it is alive.
+        unsafe { ptr.write_bytes(0x01, 1) };
+        let allocator = unsafe { MMFrameAllocator::from_raw_state(ptr) };
+        assert!(!allocator.verify());
+    }
+}
@@ -0,0 +1,112 @@
+use bitmap_allocator::BitAlloc;
+
+use crate::bitmap::BitAlloc512;
+
+/// Allocator of 512 ids, enough for task and process ids.
+pub type IdAllocator512 = IdAllocator<BitAlloc512>;
+
+/// Allocator of small integer ids, backed by a [`BitAlloc`].
+///
+/// Ids are handed out lowest first and can be recycled with [`IdAllocator::free_id`].
+/// Use [`IdAllocator::init_skip`] to keep the lowest ids (e.g. id 0) reserved.
+pub struct IdAllocator<B: BitAlloc> {
+    inner: B,
+    /// Ids below this are reserved and never handed out.
+    skip: usize,
+    allocated: usize,
+}
+
+impl<B: BitAlloc> IdAllocator<B> {
+    /// Constructs an allocator with every id free.
+    pub fn new() -> Self {
+        Self::init_skip(0)
+    }
+
+    /// Constructs an allocator with ids `0..n` reserved.
+    pub fn init_skip(n: usize) -> Self {
+        let mut inner = B::default();
+        inner.insert(n.min(B::CAP)..B::CAP);
+        Self {
+            inner,
+            skip: n,
+            allocated: 0,
+        }
+    }
+
+    /// Allocate the lowest free id.
+    pub fn alloc_id(&mut self) -> Option<usize> {
+        let id = self.inner.alloc()?;
+        self.allocated += 1;
+        Some(id)
+    }
+
+    /// Free an allocated id, returning `Err(id)` if it is not allocated.
+    pub fn free_id(&mut self, id: usize) -> Result<(), usize> {
+        if !self.is_allocated(id) {
+            return Err(id);
+        }
+        self.inner.dealloc(id);
+        self.allocated -= 1;
+        Ok(())
+    }
+
+    /// Whether `id` is currently allocated, reserved ids are not.
+    pub fn is_allocated(&self, id: usize) -> bool {
+        (self.skip..B::CAP).contains(&id) && !self.inner.test(id)
+    }
+
+    /// Number of ids currently allocated.
+    pub fn allocated_count(&self) -> usize {
+        self.allocated
+    }
+}
+
+impl<B: BitAlloc> Default for IdAllocator<B> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exhaustion() {
+        let mut ids = IdAllocator512::new();
+        for i in 0..512 {
+            assert_eq!(ids.alloc_id(), Some(i));
+        }
+        assert_eq!(ids.alloc_id(), None);
+        assert_eq!(ids.allocated_count(), 512);
+    }
+
+    #[test]
+    fn recycling() {
+        let mut ids = IdAllocator512::init_skip(1);
+        assert!(!ids.is_allocated(0));
+        assert_eq!(ids.alloc_id(), Some(1));
+        assert_eq!(ids.alloc_id(), Some(2));
+        assert_eq!(ids.alloc_id(), Some(3));
+
+        assert_eq!(ids.free_id(2), Ok(()));
+        assert!(!ids.is_allocated(2));
+        assert_eq!(ids.allocated_count(), 2);
+        assert_eq!(ids.alloc_id(), Some(2));
+        assert_eq!(ids.alloc_id(), Some(4));
+        assert_eq!(ids.allocated_count(), 4);
+    }
+
+    #[test]
+    fn double_free() {
+        let mut ids = IdAllocator512::init_skip(1);
+        let id = ids.alloc_id().unwrap();
+        assert_eq!(ids.free_id(id), Ok(()));
+        assert_eq!(ids.free_id(id), Err(id));
+        // Reserved and out-of-range ids are never allocated.
+        assert_eq!(ids.free_id(0), Err(0));
+        assert_eq!(ids.free_id(512), Err(512));
+        assert_eq!(ids.allocated_count(), 0);
+        assert_eq!(ids.alloc_id(), Some(1));
+    }
+}
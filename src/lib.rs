@@ -4,11 +4,18 @@
 extern crate log;
 
 mod addrs;
-mod bitmap;
 mod configs;
 mod structs;
 
+pub mod bitmap;
 pub mod bitmap_allocator;
+#[cfg(feature = "test-harness")]
+pub mod harness;
+pub mod id_allocator;
+pub mod util;
+
+#[cfg(test)]
+mod test_util;
 
 pub use addrs::*;
 pub use configs::*;
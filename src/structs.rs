@@ -1,9 +1,14 @@
 use core::mem::size_of;
+use core::sync::atomic::{AtomicU64, Ordering};
 
+use allocator::AllocResult;
 use memory_addr::{PAGE_SIZE_2M, PAGE_SIZE_4K, VirtAddr, align_up, align_up_4k};
 
-use crate::addrs::PROCESS_INNER_REGION_BASE_VA;
-use crate::bitmap_allocator::SegmentBitmapPageAllocator;
+use crate::addrs::{
+    GUEST_MEM_REGION_BASE_PA, GUEST_PT_ROOT_PA, GuestMappingType, PROCESS_INNER_REGION_BASE_VA,
+    SHIM_PHYS_VIRT_OFFSET,
+};
+use crate::bitmap_allocator::{PageAllocator, SegmentBitmapPageAllocator};
 use crate::{MM_FRAME_ALLOCATOR_SIZE, PT_FRAME_ALLOCATOR_SIZE};
 
 pub type MMFrameAllocator = SegmentBitmapPageAllocator<MM_FRAME_ALLOCATOR_SIZE>;
@@ -15,19 +20,30 @@ pub const PROCESS_INNER_REGION_SIZE: usize =
 pub const INSTANCE_INNER_REGION_SIZE: usize = align_up_4k(size_of::<InstanceInnerRegion>());
 pub const INSTANCE_SHARED_REGION_SIZE: usize = align_up_4k(size_of::<InstanceSharedRegion>());
 
+/// Process specific region, shared by all threads in the same process.
+///
+/// Any thread of the process may read the region concurrently. The metadata
+/// fields are written only before the process starts running: the ID, the
+/// primary flag and the mapping type by [`ProcessInnerRegion::init`], which
+/// zeroes `entry` and `stack_top`, and those two by the loader once it has
+/// placed the process image. The frame allocators are not synchronized: they must only be
+/// mutated by a single owner at a time, which is why [`process_inner_region_mut`]
+/// must not be called from two contexts at once.
 #[repr(C, align(4096))]
 pub struct ProcessInnerRegion {
     /// The process ID of the process that owns this region.
     pub process_id: usize,
     /// Whether this is the primary process.
     pub is_primary: bool,
-    /// The entry point of the process.
+    /// The entry point of the process, set by the loader.
     pub entry: usize,
-    /// The stack pointer of the process.
+    /// The stack pointer of the process, set by the loader.
     pub stack_top: usize,
     /// Manage LibOS's memory addrspace at 2MB/1GB granularity.
     /// If zero, it means One2One mapping.
-    pub mm_region_granularity: usize,
+    ///
+    /// Only ever written from a [`GuestMappingType`], see [`ProcessInnerRegion::set_mm_granularity`].
+    mm_region_granularity: usize,
     /// 2MB (4k*512) for each segment.
     /// 64 * 2MB = 128 MB in total.
     pub mm_frame_allocator: MMFrameAllocator,
@@ -37,15 +53,45 @@ pub struct ProcessInnerRegion {
     // Stack will be placed here.
 }
 
+/// Memory usage of a process across both frame allocators, in bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryReport {
+    pub mm_used_bytes: usize,
+    pub mm_total_bytes: usize,
+    pub pt_used_bytes: usize,
+    pub pt_total_bytes: usize,
+    /// Used bytes of both allocators.
+    pub used_bytes: usize,
+    /// Total bytes of both allocators.
+    pub total_bytes: usize,
+}
+
+impl core::fmt::Display for MemoryReport {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "mm {:#x}/{:#x}, pt {:#x}/{:#x}, total {:#x}/{:#x} bytes (used/total)",
+            self.mm_used_bytes,
+            self.mm_total_bytes,
+            self.pt_used_bytes,
+            self.pt_total_bytes,
+            self.used_bytes,
+            self.total_bytes
+        )
+    }
+}
+
 impl core::fmt::Debug for ProcessInnerRegion {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         writeln!(f, "ProcessInnerRegion [{}]", self.process_id)?;
         writeln!(f, "  is_primary: {}", self.is_primary)?;
         writeln!(f, "  entry: {:#x}", self.entry)?;
         writeln!(f, "  stack_top: {:#x}", self.stack_top)?;
+        let mapping = GuestMappingType::try_from(self.mm_region_granularity)
+            .map_or("invalid", |gty| gty.name());
         writeln!(
             f,
-            "  mm_region_granularity: {:#x}",
+            "  mm_region_granularity: {:#x} ({mapping})",
             self.mm_region_granularity
         )?;
         writeln!(
@@ -63,7 +109,38 @@ impl core::fmt::Debug for ProcessInnerRegion {
     }
 }
 
+// Field offsets of the region structs are an ABI: the regions are mapped at fixed
+// addresses and read by components that don't share these definitions.
+//
+// `ProcessInnerRegion` metadata is read through `PROCESS_INNER_REGION_BASE_VA`.
+const _: () = {
+    use core::mem::offset_of;
+    assert!(offset_of!(ProcessInnerRegion, process_id) == 0);
+    assert!(offset_of!(ProcessInnerRegion, is_primary) == 8);
+    assert!(offset_of!(ProcessInnerRegion, entry) == 16);
+    assert!(offset_of!(ProcessInnerRegion, stack_top) == 24);
+    assert!(offset_of!(ProcessInnerRegion, mm_region_granularity) == 32);
+    // `InstanceInnerRegion` is read through `INSTANCE_INNER_REGION_BASE_VA`.
+    assert!(offset_of!(InstanceInnerRegion, instance_id) == 0);
+    assert!(offset_of!(InstanceInnerRegion, process_num) == 8);
+    assert!(offset_of!(InstanceInnerRegion, shared_flags) == 16);
+    assert!(offset_of!(InstanceInnerRegion, barrier) == 24);
+    // `InstanceSharedRegion` is written by the owning CPU and read by the instances
+    // through `INSTANCE_SHARED_REGION_BASE_VA`.
+    assert!(offset_of!(InstanceSharedRegion, instance_id) == 0);
+    assert!(offset_of!(InstanceSharedRegion, process_id) == 8);
+};
+
+// The stack shares the region with `ProcessInnerRegion`, make sure it isn't squeezed out as the struct grows.
+const _: () = assert!(
+    ProcessInnerRegion::STACK_SIZE >= 16 * 1024,
+    "ProcessInnerRegion leaves less than 16KB for the stack"
+);
+
 impl ProcessInnerRegion {
+    /// Size of the stack placed right after the structure in the region.
+    pub const STACK_SIZE: usize = PROCESS_INNER_REGION_SIZE - size_of::<Self>();
+
     pub fn from_raw_addr_mut(addr: usize) -> &'static mut Self {
         let addr = VirtAddr::from_usize(addr);
         // SAFETY: The caller must ensure that the address is valid and points to a ProcessInnerRegion.
@@ -78,9 +155,145 @@ impl ProcessInnerRegion {
             .expect("Failed to convert raw pointer to ProcessInnerRegion")
     }
 
+    /// Initialize the region in place.
+    ///
+    /// The whole structure is zeroed first, so that neither allocator inherits
+    /// whatever the underlying memory held, then the metadata fields are set and
+    /// both frame allocators are initialized with their first segment. The MM frame
+    /// allocator's segments follow `mm_granularity`.
+    ///
+    /// Panics on [`GuestMappingType::One2OneMapping`], which is not segmented.
+    pub fn init(&mut self, process_id: usize, is_primary: bool, mm_granularity: GuestMappingType) {
+        // SAFETY: `self` is a valid exclusive reference, and all-zero is a valid
+        // bit pattern for every field of `ProcessInnerRegion`.
+        unsafe { core::ptr::write_bytes(self as *mut Self, 0, 1) };
+
+        self.process_id = process_id;
+        self.is_primary = is_primary;
+        self.set_mm_granularity(mm_granularity);
+
+        self.init_frame_allocators(mm_granularity, (PAGE_SIZE_4K, PAGE_SIZE_2M));
+    }
+
+    /// Initialize the region in place for a secondary process forked from `primary`.
+    ///
+    /// The mapping granularity and the frame allocators' configuration are taken
+    /// from `primary`, but the allocators themselves start out fresh, holding only
+    /// their first segment, so the two processes never share frames.
+    pub fn init_secondary_from(&mut self, primary: &ProcessInnerRegion, process_id: usize) {
+        // SAFETY: `self` is a valid exclusive reference, and all-zero is a valid
+        // bit pattern for every field of `ProcessInnerRegion`.
+        unsafe { core::ptr::write_bytes(self as *mut Self, 0, 1) };
+
+        self.process_id = process_id;
+        self.is_primary = false;
+        self.mm_region_granularity = primary.mm_region_granularity;
+
+        let pt = &primary.pt_frame_allocator;
+        self.init_frame_allocators(
+            primary.mm_granularity(),
+            (pt.page_size(), pt.segment_granularity()),
+        );
+    }
+
+    /// Initialize both frame allocators with their first segment, the MM one
+    /// segmented by `mm_granularity` and the PT one given as a
+    /// `(page_size, segment_granularity)` pair.
+    fn init_frame_allocators(
+        &mut self,
+        mm_granularity: GuestMappingType,
+        pt_config: (usize, usize),
+    ) {
+        self.mm_frame_allocator.init_with_mapping_type(
+            mm_granularity,
+            GUEST_MEM_REGION_BASE_PA,
+            mm_granularity.granularity_bytes(),
+        );
+        let (page_size, segment_granularity) = pt_config;
+        self.pt_frame_allocator.init_with_page_size(
+            page_size,
+            segment_granularity,
+            GUEST_PT_ROOT_PA,
+            segment_granularity,
+        );
+        self.reserve_pt_root();
+    }
+
+    /// Reserve the page at [`GUEST_PT_ROOT_PA`], which the process's CR3 points to,
+    /// so that the PT frame allocator never hands it out, not even after a reset.
+    fn reserve_pt_root(&mut self) {
+        self.pt_frame_allocator
+            .reserve_permanent(GUEST_PT_ROOT_PA, 1)
+            .expect("Failed to reserve the guest page table root");
+    }
+
+    /// Memory usage across both frame allocators.
+    pub fn memory_report(&self) -> MemoryReport {
+        let (mm, pt) = (&self.mm_frame_allocator, &self.pt_frame_allocator);
+        MemoryReport {
+            mm_used_bytes: mm.used_bytes(),
+            mm_total_bytes: mm.total_bytes(),
+            pt_used_bytes: pt.used_bytes(),
+            pt_total_bytes: pt.total_bytes(),
+            used_bytes: mm.used_bytes() + pt.used_bytes(),
+            total_bytes: mm.total_bytes() + pt.total_bytes(),
+        }
+    }
+
+    /// Borrow both frame allocators at once.
+    pub fn frame_allocators_mut(&mut self) -> (&mut MMFrameAllocator, &mut PTFrameAllocator) {
+        (&mut self.mm_frame_allocator, &mut self.pt_frame_allocator)
+    }
+
+    /// Allocate a TLS block of at least `size` bytes from the process's frames.
+    ///
+    /// Returns the GVA of the block, to be used as a thread's TLS area.
+    ///
+    /// The block is rounded up to, and aligned on, the page size of the mm frame
+    /// allocator.
+    pub fn alloc_tls(&mut self, size: usize) -> AllocResult<usize> {
+        let page_size = self.mm_frame_allocator.page_size();
+        self.mm_frame_allocator
+            .alloc_pages(align_up(size, page_size) / page_size, page_size)
+            .map(|gpa| gpa + SHIM_PHYS_VIRT_OFFSET)
+    }
+
+    /// Get the mapping type of LibOS's memory addrspace.
+    pub fn mm_granularity(&self) -> GuestMappingType {
+        GuestMappingType::try_from(self.mm_region_granularity)
+            .expect("Invalid mm_region_granularity in ProcessInnerRegion")
+    }
+
+    /// Set the mapping type of LibOS's memory addrspace.
+    pub fn set_mm_granularity(&mut self, gty: GuestMappingType) {
+        self.mm_region_granularity = gty.granularity_bytes();
+    }
+
+    /// FNV-1a hash of the metadata fields, used to detect the region being clobbered.
+    ///
+    /// The frame allocators are not covered, since they legitimately change
+    /// whenever the process allocates.
+    pub fn checksum(&self) -> u64 {
+        const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+        const FNV_PRIME: u64 = 0x0100_0000_01b3;
+
+        [
+            self.process_id,
+            self.is_primary as usize,
+            self.entry,
+            self.stack_top,
+            self.mm_region_granularity,
+        ]
+        .iter()
+        .flat_map(|field| field.to_ne_bytes())
+        .fold(FNV_OFFSET_BASIS, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(FNV_PRIME)
+        })
+    }
+
     /// Get the stack top address of the process.
     ///
-    /// stack size = 2MB - size_of::<ProcessInnerRegion>()
+    /// The stack is [`Self::STACK_SIZE`] bytes and grows down from here.
     pub fn stack_top(&self) -> usize {
         self as *const _ as usize + PROCESS_INNER_REGION_SIZE - 8
     }
@@ -95,17 +308,26 @@ pub fn process_inner_region_mut() -> &'static mut ProcessInnerRegion {
 }
 
 pub fn mm_region_granularity() -> usize {
-    process_inner_region().mm_region_granularity
+    process_inner_region().mm_granularity().granularity_bytes()
 }
 
+/// Each call takes a new mutable reference to the whole region, so this must not be
+/// held together with [`pt_frame_allocator`]; use [`frame_allocators`] instead.
 pub fn mm_frame_allocator() -> &'static mut MMFrameAllocator {
     &mut process_inner_region_mut().mm_frame_allocator
 }
 
+/// Each call takes a new mutable reference to the whole region, so this must not be
+/// held together with [`mm_frame_allocator`]; use [`frame_allocators`] instead.
 pub fn pt_frame_allocator() -> &'static mut PTFrameAllocator {
     &mut process_inner_region_mut().pt_frame_allocator
 }
 
+/// Both frame allocators, borrowed from a single reference to the region.
+pub fn frame_allocators() -> (&'static mut MMFrameAllocator, &'static mut PTFrameAllocator) {
+    process_inner_region_mut().frame_allocators_mut()
+}
+
 pub fn is_primary() -> bool {
     process_inner_region().is_primary
 }
@@ -114,15 +336,55 @@ pub fn process_id() -> usize {
     process_inner_region().process_id
 }
 
+/// Instance specific region, shared by all processes in the same instance.
+///
+/// The ids are written once when the instance is created and are read-only
+/// afterwards, so concurrent readers need no synchronization. The atomic fields
+/// are for coordination between the processes of the instance.
 #[repr(C)]
 pub struct InstanceInnerRegion {
     /// The instance ID of the instance that owns this region.
     pub instance_id: u64,
     /// The process number.
     pub process_num: u64,
+    /// Flags shared by all processes of the instance.
+    pub shared_flags: AtomicU64,
+    /// Number of processes that arrived at the barrier, see [`InstanceInnerRegion::arrive_barrier`].
+    pub barrier: AtomicU64,
+}
+
+impl InstanceInnerRegion {
+    /// Set the shared flag `bit`, panics unless it is in `0..64`.
+    pub fn set_flag(&self, bit: u32) {
+        self.shared_flags
+            .fetch_or(Self::flag_mask(bit), Ordering::Release);
+    }
+
+    /// Test the shared flag `bit`, panics unless it is in `0..64`.
+    pub fn test_flag(&self, bit: u32) -> bool {
+        self.shared_flags.load(Ordering::Acquire) & Self::flag_mask(bit) != 0
+    }
+
+    fn flag_mask(bit: u32) -> u64 {
+        assert!(bit < u64::BITS, "shared flag {bit} out of range");
+        1 << bit
+    }
+
+    /// Arrive at the barrier, then wait until `expected` processes have arrived.
+    ///
+    /// The barrier is one-shot: it is not reset once everyone has passed.
+    pub fn arrive_barrier(&self, expected: u64) {
+        self.barrier.fetch_add(1, Ordering::AcqRel);
+        while self.barrier.load(Ordering::Acquire) < expected {
+            core::hint::spin_loop();
+        }
+    }
 }
 
 /// The structure of the memory region.
+///
+/// This is a percpu region: only the owning CPU writes it, while instances
+/// running on that CPU may read it.
 #[repr(C, packed)]
 #[derive(Debug, Clone, Copy, Default)]
 pub struct InstanceSharedRegion {
@@ -131,3 +393,370 @@ pub struct InstanceSharedRegion {
     /// The ID of the process that are running on this CPU.
     pub process_id: u64,
 }
+
+/// Header of the raw instance config blob, as laid out for the shim.
+///
+/// The words are kept raw, the getters validate them.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ConfigHeader {
+    /// Granularity of the guest mapping in bytes, see [`GuestMappingType`].
+    pub mm_granularity: u64,
+}
+
+impl ConfigHeader {
+    /// Read the header from the start of a config blob, `None` if it is too short.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let word = bytes.get(..size_of::<u64>())?;
+        Some(Self {
+            mm_granularity: u64::from_ne_bytes(word.try_into().unwrap()),
+        })
+    }
+
+    /// The raw bytes of the header, as laid out in a config blob.
+    pub fn as_bytes(&self) -> [u8; size_of::<Self>()] {
+        self.mm_granularity.to_ne_bytes()
+    }
+
+    /// Get the mapping type, or the raw word back if it is invalid.
+    pub fn mm_granularity(&self) -> Result<GuestMappingType, u64> {
+        GuestMappingType::from_config_word(self.mm_granularity)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::mem::MaybeUninit;
+
+    use super::*;
+
+    fn zeroed_process_region() -> ProcessInnerRegion {
+        // SAFETY: all-zero is a valid bit pattern for every field of
+        // `ProcessInnerRegion`, see `ProcessInnerRegion::init`.
+        unsafe { core::mem::zeroed() }
+    }
+
+    fn zeroed_instance_region() -> InstanceInnerRegion {
+        // SAFETY: `InstanceInnerRegion` only holds integers and atomics, for which
+        // all-zero is valid.
+        unsafe { core::mem::zeroed() }
+    }
+    use crate::test_util::display_in;
+
+    #[test]
+    fn regions_are_send_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+
+        assert_send_sync::<ProcessInnerRegion>();
+        assert_send_sync::<InstanceInnerRegion>();
+        assert_send_sync::<InstanceSharedRegion>();
+    }
+
+    #[test]
+    fn process_inner_region_init() {
+        let mut region = MaybeUninit::<ProcessInnerRegion>::uninit();
+        // Fill with garbage that is still a valid bit pattern for every field.
+        // SAFETY: the pointer is valid for one `ProcessInnerRegion`, and a byte of
+        // 0x01 makes `is_primary` true and every other field an integer.
+        unsafe { region.as_mut_ptr().write_bytes(0x01, 1) };
+        // SAFETY: every byte was just initialized to a valid pattern.
+        let region = unsafe { region.assume_init_mut() };
+
+        region.init(3, false, GuestMappingType::CoarseGrainedSegmentation2M);
+        assert_eq!(region.process_id, 3);
+        assert!(!region.is_primary);
+        assert_eq!(region.entry, 0);
+        assert_eq!(region.mm_region_granularity, PAGE_SIZE_2M);
+
+        assert_eq!(region.mm_frame_allocator.used_pages(), 0);
+        assert_eq!(region.mm_frame_allocator.total_pages(), 512);
+        // The page table root is reserved rather than allocated.
+        assert_eq!(region.pt_frame_allocator.used_pages(), 0);
+        assert_eq!(region.pt_frame_allocator.available_pages(), 511);
+        assert_eq!(region.pt_frame_allocator.total_pages(), 512);
+
+        let addr = region
+            .mm_frame_allocator
+            .alloc_pages(1, PAGE_SIZE_4K)
+            .unwrap();
+        assert_eq!(addr, GUEST_MEM_REGION_BASE_PA);
+    }
+
+    #[test]
+    fn process_inner_region_init_1g() {
+        let mut region = zeroed_process_region();
+        region.init(1, true, GuestMappingType::CoarseGrainedSegmentation1G);
+
+        let allocator = &mut region.mm_frame_allocator;
+        assert_eq!(allocator.segment_granularity(), memory_addr::PAGE_SIZE_1G);
+        assert_eq!(PageAllocator::page_size(allocator), PAGE_SIZE_2M);
+        assert_eq!(allocator.total_pages(), 512);
+        assert_eq!(
+            allocator.alloc_pages(1, PAGE_SIZE_2M),
+            Ok(GUEST_MEM_REGION_BASE_PA)
+        );
+    }
+
+    #[test]
+    fn pt_root_is_never_allocated() {
+        let mut region = zeroed_process_region();
+        region.init(1, true, GuestMappingType::CoarseGrainedSegmentation2M);
+
+        let allocator = &mut region.pt_frame_allocator;
+        assert!(
+            allocator
+                .alloc_pages_at(GUEST_PT_ROOT_PA, 1, PAGE_SIZE_4K)
+                .is_err()
+        );
+        // Freeing it is rejected.
+        allocator.dealloc_pages(GUEST_PT_ROOT_PA, 1);
+        assert_eq!(allocator.used_pages(), 0);
+        while let Ok(addr) = allocator.alloc_pages(1, PAGE_SIZE_4K) {
+            assert_ne!(addr, GUEST_PT_ROOT_PA);
+        }
+        assert_eq!(allocator.used_pages(), allocator.total_pages() - 1);
+    }
+
+    #[test]
+    fn pt_root_survives_reset() {
+        let mut region = zeroed_process_region();
+        region.init(1, true, GuestMappingType::CoarseGrainedSegmentation2M);
+
+        let allocator = &mut region.pt_frame_allocator;
+        allocator.alloc_pages(4, PAGE_SIZE_4K).unwrap();
+        allocator.reset();
+        assert_eq!(allocator.used_pages(), 0);
+        while let Ok(addr) = allocator.alloc_pages(1, PAGE_SIZE_4K) {
+            assert_ne!(addr, GUEST_PT_ROOT_PA);
+        }
+        assert_eq!(allocator.available_pages(), 0);
+    }
+
+    #[test]
+    fn init_secondary_from() {
+        let mut primary = zeroed_process_region();
+        primary.init(1, true, GuestMappingType::CoarseGrainedSegmentation2M);
+        primary
+            .mm_frame_allocator
+            .alloc_pages(10, PAGE_SIZE_4K)
+            .unwrap();
+
+        let mut secondary = MaybeUninit::<ProcessInnerRegion>::uninit();
+        // SAFETY: as in `process_inner_region_init`, 0x01 bytes are a valid pattern.
+        unsafe { secondary.as_mut_ptr().write_bytes(0x01, 1) };
+        // SAFETY: every byte was just initialized to a valid pattern.
+        let secondary = unsafe { secondary.assume_init_mut() };
+        secondary.init_secondary_from(&primary, 2);
+
+        assert_eq!(secondary.process_id, 2);
+        assert!(!secondary.is_primary);
+        assert_eq!(secondary.mm_granularity(), primary.mm_granularity());
+        for (ours, theirs) in [
+            (
+                secondary.mm_frame_allocator.stats(),
+                primary.mm_frame_allocator.stats(),
+            ),
+            (
+                secondary.pt_frame_allocator.stats(),
+                primary.pt_frame_allocator.stats(),
+            ),
+        ] {
+            assert_eq!(ours.base, theirs.base);
+            assert_eq!(ours.page_size, theirs.page_size);
+            assert_eq!(ours.total_pages, theirs.total_pages);
+        }
+        assert_eq!(
+            secondary.mm_frame_allocator.segment_granularity(),
+            primary.mm_frame_allocator.segment_granularity()
+        );
+
+        // The allocators are independent.
+        assert_eq!(secondary.mm_frame_allocator.used_pages(), 0);
+        assert_eq!(secondary.pt_frame_allocator.used_pages(), 0);
+        assert_eq!(
+            secondary.mm_frame_allocator.alloc_pages(1, PAGE_SIZE_4K),
+            Ok(GUEST_MEM_REGION_BASE_PA)
+        );
+        assert_eq!(primary.mm_frame_allocator.used_pages(), 10);
+    }
+
+    #[test]
+    fn alloc_tls() {
+        let mut region = zeroed_process_region();
+        region.init(1, true, GuestMappingType::CoarseGrainedSegmentation2M);
+
+        let tls = region.alloc_tls(0x1800).unwrap();
+        assert_eq!(tls, crate::GUEST_MEMORY_REGION_BASE_VA);
+        assert_eq!(tls % PAGE_SIZE_4K, 0);
+        assert_eq!(region.mm_frame_allocator.used_pages(), 2);
+        assert_eq!(
+            region.alloc_tls(PAGE_SIZE_4K),
+            Ok(crate::GUEST_MEMORY_REGION_BASE_VA + 2 * PAGE_SIZE_4K)
+        );
+        assert_eq!(region.mm_frame_allocator.used_pages(), 3);
+    }
+
+    #[test]
+    fn alloc_tls_1g() {
+        let mut region = zeroed_process_region();
+        region.init(1, true, GuestMappingType::CoarseGrainedSegmentation1G);
+
+        let tls = region.alloc_tls(PAGE_SIZE_4K).unwrap();
+        assert_eq!(tls, crate::GUEST_MEMORY_REGION_BASE_VA);
+        assert_eq!(region.mm_frame_allocator.used_pages(), 1);
+        assert_eq!(
+            region.alloc_tls(PAGE_SIZE_2M + 1),
+            Ok(crate::GUEST_MEMORY_REGION_BASE_VA + PAGE_SIZE_2M)
+        );
+        assert_eq!(region.mm_frame_allocator.used_pages(), 3);
+    }
+
+    #[test]
+    fn instance_inner_region_coordination() {
+        let region = zeroed_instance_region();
+        assert!(!region.test_flag(3));
+        region.set_flag(3);
+        region.set_flag(63);
+        assert!(region.test_flag(3));
+        assert!(region.test_flag(63));
+        assert!(!region.test_flag(4));
+
+        // The only process of the instance passes right away.
+        region.arrive_barrier(1);
+        assert_eq!(region.barrier.load(Ordering::Relaxed), 1);
+
+        assert_eq!(INSTANCE_INNER_REGION_SIZE % PAGE_SIZE_4K, 0);
+        assert!(INSTANCE_INNER_REGION_SIZE >= size_of::<InstanceInnerRegion>());
+    }
+
+    #[test]
+    #[should_panic(expected = "shared flag 64 out of range")]
+    fn instance_inner_region_flag_out_of_range() {
+        let region = zeroed_instance_region();
+        region.set_flag(64);
+    }
+
+    #[test]
+    fn frame_allocators_mut() {
+        let mut region = zeroed_process_region();
+        region.init(1, true, GuestMappingType::CoarseGrainedSegmentation2M);
+
+        let (mm, pt) = region.frame_allocators_mut();
+        let frame = mm.alloc_pages(1, PAGE_SIZE_4K).unwrap();
+        let table = pt.alloc_pages(1, PAGE_SIZE_4K).unwrap();
+        assert_eq!(frame, GUEST_MEM_REGION_BASE_PA);
+        assert_eq!(table, GUEST_PT_ROOT_PA + PAGE_SIZE_4K);
+
+        assert_eq!(region.mm_frame_allocator.used_pages(), 1);
+        assert_eq!(region.pt_frame_allocator.used_pages(), 1);
+    }
+
+    #[test]
+    fn memory_report() {
+        let mut region = zeroed_process_region();
+        region.init(1, true, GuestMappingType::CoarseGrainedSegmentation2M);
+        region
+            .mm_frame_allocator
+            .alloc_pages(3, PAGE_SIZE_4K)
+            .unwrap();
+
+        let report = region.memory_report();
+        assert_eq!(
+            report,
+            MemoryReport {
+                mm_used_bytes: 3 * PAGE_SIZE_4K,
+                mm_total_bytes: PAGE_SIZE_2M,
+                pt_used_bytes: 0,
+                pt_total_bytes: PAGE_SIZE_2M,
+                used_bytes: 3 * PAGE_SIZE_4K,
+                total_bytes: 2 * PAGE_SIZE_2M,
+            }
+        );
+
+        assert_eq!(
+            display_in(&mut [0; 128], report),
+            "mm 0x3000/0x200000, pt 0x0/0x200000, total 0x3000/0x400000 bytes (used/total)"
+        );
+    }
+
+    #[test]
+    fn stack_size() {
+        let region = zeroed_process_region();
+        let start = &region as *const _ as usize;
+        let stack_bottom = start + size_of::<ProcessInnerRegion>();
+        assert_eq!(
+            region.stack_top() + 8 - stack_bottom,
+            ProcessInnerRegion::STACK_SIZE
+        );
+    }
+
+    #[test]
+    fn set_mm_granularity() {
+        // SAFETY: all-zero is a valid `ProcessInnerRegion`.
+        let mut region = zeroed_process_region();
+        for (gty, bytes) in [
+            (GuestMappingType::One2OneMapping, 0),
+            (GuestMappingType::CoarseGrainedSegmentation2M, PAGE_SIZE_2M),
+            (
+                GuestMappingType::CoarseGrainedSegmentation1G,
+                memory_addr::PAGE_SIZE_1G,
+            ),
+        ] {
+            region.set_mm_granularity(gty);
+            assert_eq!(region.mm_region_granularity, bytes);
+            assert_eq!(region.mm_granularity(), gty);
+        }
+        assert_eq!(GuestMappingType::try_from(PAGE_SIZE_4K), Err(PAGE_SIZE_4K));
+    }
+
+    #[test]
+    fn config_header() {
+        let header = ConfigHeader {
+            mm_granularity: PAGE_SIZE_2M as u64,
+        };
+        let mut blob = [0u8; 16];
+        blob[..8].copy_from_slice(&header.as_bytes());
+        let decoded = ConfigHeader::from_bytes(&blob).unwrap();
+        assert_eq!(
+            decoded.mm_granularity(),
+            Ok(GuestMappingType::CoarseGrainedSegmentation2M)
+        );
+
+        let invalid = ConfigHeader::from_bytes(&(PAGE_SIZE_4K as u64).to_ne_bytes()).unwrap();
+        assert_eq!(invalid.mm_granularity(), Err(PAGE_SIZE_4K as u64));
+        assert!(ConfigHeader::from_bytes(&blob[..7]).is_none());
+    }
+
+    #[test]
+    fn checksum() {
+        #[repr(C)]
+        struct RegionWithStack {
+            region: ProcessInnerRegion,
+            stack: [u8; 64],
+        }
+
+        // SAFETY: all-zero is valid for the region, see `zeroed_process_region`, and
+        // for the byte array.
+        let mut memory: RegionWithStack = unsafe { core::mem::zeroed() };
+        memory
+            .region
+            .init(1, true, GuestMappingType::CoarseGrainedSegmentation2M);
+        let checksum = memory.region.checksum();
+
+        // Neither the stack nor the allocators are covered.
+        memory.stack.fill(0xff);
+        memory
+            .region
+            .mm_frame_allocator
+            .alloc_pages(1, PAGE_SIZE_4K)
+            .unwrap();
+        assert_eq!(memory.region.checksum(), checksum);
+
+        memory.region.entry = 0x1000;
+        assert_ne!(memory.region.checksum(), checksum);
+        memory.region.entry = 0;
+        assert_eq!(memory.region.checksum(), checksum);
+        memory.region.is_primary = false;
+        assert_ne!(memory.region.checksum(), checksum);
+    }
+}
@@ -0,0 +1,27 @@
+//! Helpers shared by the unit tests.
+
+use core::fmt::{Display, Write};
+
+/// Format `value` into `buf`, returning the written part.
+///
+/// Panics if `buf` is too short.
+pub fn display_in(buf: &mut [u8], value: impl Display) -> &str {
+    struct BufWriter<'a>(&'a mut [u8], usize);
+
+    impl Write for BufWriter<'_> {
+        fn write_str(&mut self, s: &str) -> core::fmt::Result {
+            let end = self.1 + s.len();
+            self.0
+                .get_mut(self.1..end)
+                .ok_or(core::fmt::Error)?
+                .copy_from_slice(s.as_bytes());
+            self.1 = end;
+            Ok(())
+        }
+    }
+
+    let mut writer = BufWriter(buf, 0);
+    write!(writer, "{value}").expect("buffer too short");
+    let len = writer.1;
+    core::str::from_utf8(&buf[..len]).unwrap()
+}
@@ -0,0 +1,45 @@
+//! Alignment helpers for alignments given as a power of two exponent.
+//!
+//! An `align_log2` of 0 means no alignment at all.
+
+/// Round `base` up to a multiple of `1 << align_log2`.
+///
+/// `align_log2` must be less than `usize::BITS`, and the result must fit in a `usize`.
+pub const fn align_up_log2(base: usize, align_log2: usize) -> usize {
+    (base + ((1 << align_log2) - 1)) & !((1 << align_log2) - 1)
+}
+
+/// Whether `base` is a multiple of `1 << align_log2`.
+///
+/// `align_log2` must be less than `usize::BITS`.
+pub const fn is_aligned_log2(base: usize, align_log2: usize) -> bool {
+    (base & ((1 << align_log2) - 1)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn align_log2() {
+        // No alignment.
+        for base in [0, 1, 7, usize::MAX] {
+            assert_eq!(align_up_log2(base, 0), base);
+            assert!(is_aligned_log2(base, 0));
+        }
+
+        assert_eq!(align_up_log2(0, 3), 0);
+        assert_eq!(align_up_log2(1, 3), 8);
+        assert_eq!(align_up_log2(8, 3), 8);
+        assert_eq!(align_up_log2(9, 3), 16);
+        assert!(is_aligned_log2(16, 3));
+        assert!(!is_aligned_log2(12, 3));
+
+        let max_log2 = usize::BITS as usize - 1;
+        assert_eq!(align_up_log2(0, max_log2), 0);
+        assert_eq!(align_up_log2(1, max_log2), 1 << max_log2);
+        assert!(is_aligned_log2(1 << max_log2, max_log2));
+        assert!(!is_aligned_log2(1 << (max_log2 - 1), max_log2));
+        assert!(!is_aligned_log2(usize::MAX, max_log2));
+    }
+}